@@ -0,0 +1,20 @@
+//! Passes the locked lines-are-rusty revision to the crate as
+//! `LINES_ARE_RUSTY_REV`, it is part of the render cache key since updating
+//! it can change how every page renders.
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let rev = lock
+        .split("[[package]]")
+        .find(|package| package.contains("name = \"lines-are-rusty\""))
+        .and_then(|package| {
+            package
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("source = \""))
+        })
+        .and_then(|source| source.trim_end_matches('"').rsplit_once('#'))
+        .map(|(_, rev)| rev.to_string())
+        .unwrap_or_else(|| "unlocked".to_string());
+    println!("cargo:rustc-env=LINES_ARE_RUSTY_REV={}", rev);
+}
@@ -6,5 +6,7 @@ mkShell {
     openssl
     pkg-config
     sqlite
+    poppler_utils
+    calibre
   ];
 }
@@ -0,0 +1,163 @@
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use remarkable_cloud_api::Uuid;
+
+use crate::pdf::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// The PDF an annotated document was made from, rendered page by page with
+/// poppler's `pdftocairo` so the strokes can be drawn on top.
+pub struct PdfBackground {
+    pdf_path: PathBuf,
+    work_dir: PathBuf,
+}
+
+impl PdfBackground {
+    /// Extracts `<uuid>.pdf` from a document zip into `work_dir`, converting
+    /// `<uuid>.epub` with calibre's `ebook-convert` when there is no PDF.
+    pub fn extract<R: Read + Seek>(
+        zip: &mut zip::ZipArchive<R>,
+        id: Uuid,
+        work_dir: &Path,
+    ) -> Result<Option<Self>> {
+        let _ = std::fs::remove_dir_all(work_dir);
+        std::fs::create_dir_all(work_dir).context("Creating background directory")?;
+        let pdf_path = work_dir.join("source.pdf");
+
+        if extract_entry(zip, &format!("{}.pdf", id), &pdf_path)? {
+            require_tool("pdftocairo", "-v", "poppler")?;
+            return Ok(Some(Self {
+                pdf_path,
+                work_dir: work_dir.to_path_buf(),
+            }));
+        }
+
+        let epub_path = work_dir.join("source.epub");
+        if extract_entry(zip, &format!("{}.epub", id), &epub_path)? {
+            require_tool("ebook-convert", "--version", "calibre")?;
+            require_tool("pdftocairo", "-v", "poppler")?;
+            println!("Converting {} epub to pdf", id);
+            let status = Command::new("ebook-convert")
+                .arg(&epub_path)
+                .arg(&pdf_path)
+                .status()
+                .context("Running ebook-convert, is calibre installed?")?;
+            if !status.success() {
+                return Err(anyhow!("ebook-convert failed on {} epub: {}", id, status));
+            }
+            return Ok(Some(Self {
+                pdf_path,
+                work_dir: work_dir.to_path_buf(),
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Renders a (0-based) page of the PDF as an svg element covering the
+    /// tablet's screen, ready to be placed beneath the strokes.
+    pub fn page_svg(&self, page: usize) -> Result<String> {
+        let out = self.work_dir.join(format!("{}.svg", page));
+        if !out.exists() {
+            let page_arg = (page + 1).to_string();
+            let status = Command::new("pdftocairo")
+                .args(["-svg", "-f", &page_arg, "-l", &page_arg])
+                .arg(&self.pdf_path)
+                .arg(&out)
+                .status()
+                .context("Running pdftocairo, is poppler installed?")?;
+            if !status.success() {
+                return Err(anyhow!("pdftocairo failed on page {}: {}", page, status));
+            }
+        }
+        let svg = std::fs::read_to_string(&out).context("Reading background svg")?;
+        nest_svg(&svg)
+    }
+}
+
+/// Checks that an external tool can be run before any page is rendered
+/// with it, so a missing install fails with a hint instead of halfway through.
+fn require_tool(program: &str, version_arg: &str, package: &str) -> Result<()> {
+    match Command::new(program).arg(version_arg).output() {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(anyhow!(
+            "{} was not found, install {} (it is in shell.nix) to render documents with a PDF or EPUB background",
+            program,
+            package
+        )),
+        Err(e) => Err(e).with_context(|| format!("Running {}", program)),
+    }
+}
+
+fn extract_entry<R: Read + Seek>(
+    zip: &mut zip::ZipArchive<R>,
+    name: &str,
+    out: &Path,
+) -> Result<bool> {
+    let mut file = match zip.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Opening {} in zip", name)),
+    };
+    let mut out_file = std::fs::File::create(out).context("Creating extracted file")?;
+    std::io::copy(&mut file, &mut out_file).with_context(|| format!("Extracting {}", name))?;
+    Ok(true)
+}
+
+/// Rewrites the root element of a standalone svg so it can be nested inside
+/// a page, scaled to fit the screen width the way the tablet shows PDFs.
+fn nest_svg(svg: &str) -> Result<String> {
+    let start = svg
+        .find("<svg")
+        .ok_or_else(|| anyhow!("Background has no svg element"))?;
+    let tag_end = start
+        + svg[start..]
+            .find('>')
+            .ok_or_else(|| anyhow!("Background svg element is not closed"))?;
+    let root_tag = &svg[start..tag_end];
+    let view_box =
+        attribute(root_tag, "viewBox").ok_or_else(|| anyhow!("Background svg has no viewBox"))?;
+
+    Ok(format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" x="0" y="0" width="{}" height="{}" viewBox="{}" preserveAspectRatio="xMidYMin meet"{}"#,
+        SCREEN_WIDTH,
+        SCREEN_HEIGHT,
+        view_box,
+        &svg[tag_end..]
+    ))
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
+}
+
+/// Places the background beneath the strokes of a rendered page svg.
+pub fn compose(strokes_svg: &str, background: &str) -> Result<String> {
+    let start = strokes_svg
+        .find("<svg")
+        .ok_or_else(|| anyhow!("Page has no svg element"))?;
+    let tag_end = start
+        + strokes_svg[start..]
+            .find('>')
+            .ok_or_else(|| anyhow!("Page svg element is not closed"))?
+        + 1;
+    Ok(format!(
+        "{}{}{}",
+        &strokes_svg[..tag_end],
+        background,
+        &strokes_svg[tag_end..]
+    ))
+}
+
+/// An svg for a page that has no strokes, only a background.
+pub fn blank_page() -> String {
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}"></svg>"#,
+        w = SCREEN_WIDTH,
+        h = SCREEN_HEIGHT
+    )
+}
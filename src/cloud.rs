@@ -0,0 +1,233 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use remarkable_cloud_api::{reqwest, Uuid};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+const USER_TOKEN_URL: &str =
+    "https://webapp-prod.cloud.remarkable.engineering/token/json/2/user/new";
+
+const DEVICE_TOKEN_URL: &str =
+    "https://webapp-prod.cloud.remarkable.engineering/token/json/2/device/new";
+
+/// Hosts the rM cloud client talks to for authentication.
+const AUTH_HOSTS: &[&str] = &[
+    "webapp-production-dot-remarkable-production.appspot.com",
+    "webapp-prod.cloud.remarkable.engineering",
+    "my.remarkable.com",
+];
+
+/// Hosts the rM cloud client talks to for service discovery and storage.
+const STORAGE_HOSTS: &[&str] = &[
+    "service-manager-production-dot-remarkable-production.appspot.com",
+    "document-storage-production-dot-remarkable-production.appspot.com",
+    "internal.cloud.remarkable.com",
+];
+
+/// Where to find the rM cloud, defaults to the official reMarkable cloud.
+///
+/// Requests this crate makes itself are rewritten onto `cloud_url`, keeping
+/// its scheme and port. The rM cloud client only knows the official urls, so
+/// for it the official hosts are resolved to the self-hosted server the way
+/// the tablet does it. That only works for a server on https port 443 with
+/// certificates signed by `cloud_ca_cert` or by a publicly trusted CA.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, StructOpt)]
+pub struct CloudEndpoints {
+    /// Base url of a self-hosted rM cloud, e.g. an rmfakecloud instance
+    #[structopt(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_url: Option<String>,
+    /// Host serving authentication, defaults to the host of `cloud_url`
+    #[structopt(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_auth_host: Option<String>,
+    /// Host serving document storage, defaults to the host of `cloud_url`
+    #[structopt(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_storage_host: Option<String>,
+    /// PEM encoded CA certificate the self-hosted cloud's certificates are signed with
+    #[structopt(long, parse(from_os_str))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_ca_cert: Option<PathBuf>,
+}
+
+impl CloudEndpoints {
+    pub fn is_official(&self) -> bool {
+        self.cloud_url.is_none()
+            && self.cloud_auth_host.is_none()
+            && self.cloud_storage_host.is_none()
+    }
+
+    fn base_url(&self) -> Result<Option<url::Url>> {
+        match &self.cloud_url {
+            Some(cloud_url) => {
+                let url = url::Url::parse(cloud_url)
+                    .with_context(|| format!("Parsing cloud url {:?}", cloud_url))?;
+                if url.host_str().is_none() {
+                    return Err(anyhow!("Cloud url has no host: {:?}", cloud_url));
+                }
+                Ok(Some(url))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn url_host(&self) -> Result<Option<String>> {
+        Ok(self
+            .base_url()?
+            .and_then(|url| url.host_str().map(String::from)))
+    }
+
+    pub fn auth_host(&self) -> Result<Option<String>> {
+        match &self.cloud_auth_host {
+            Some(host) => Ok(Some(host.clone())),
+            None => self.url_host(),
+        }
+    }
+
+    pub fn storage_host(&self) -> Result<Option<String>> {
+        match &self.cloud_storage_host {
+            Some(host) => Ok(Some(host.clone())),
+            None => self.url_host(),
+        }
+    }
+
+    /// Rewrites `official`, a url on one of the official rM hosts, onto the
+    /// configured endpoints. An auth or storage host replaces just the host,
+    /// `cloud_url` also replaces the scheme and port and prefixes its path.
+    pub fn url(&self, official: &str) -> Result<url::Url> {
+        let mut url = url::Url::parse(official)
+            .with_context(|| format!("Parsing rM cloud url {:?}", official))?;
+        let official_host = url.host_str().unwrap_or_default();
+        let host = if AUTH_HOSTS.contains(&official_host) {
+            &self.cloud_auth_host
+        } else if STORAGE_HOSTS.contains(&official_host) {
+            &self.cloud_storage_host
+        } else {
+            return Ok(url);
+        };
+
+        if let Some(host) = host {
+            url.set_host(Some(host))
+                .with_context(|| format!("Setting cloud host {:?}", host))?;
+        } else if let Some(base) = self.base_url()? {
+            let path = format!("{}{}", base.path().trim_end_matches('/'), url.path());
+            let query = url.query().map(String::from);
+            url = base;
+            url.set_path(&path);
+            url.set_query(query.as_deref());
+        }
+        Ok(url)
+    }
+
+    /// Builds the http client for requests made through `url`.
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        self.client_builder()?
+            .build()
+            .context("Building reqwest client")
+    }
+
+    /// Builds the http client handed to the rM cloud client, resolving the
+    /// official rM hosts to the configured endpoints.
+    pub async fn resolving_http_client(&self) -> Result<reqwest::Client> {
+        if self.cloud_auth_host.is_none() || self.cloud_storage_host.is_none() {
+            if let Some(base) = self.base_url()? {
+                if base.scheme() != "https" || base.port_or_known_default() != Some(443) {
+                    return Err(anyhow!(
+                        "The rM cloud client only connects over https on port 443, \
+                         {:?} has to be served there to be used for anything but `fetch --sync15`",
+                        base.as_str()
+                    ));
+                }
+            }
+        }
+
+        let mut builder = self.client_builder()?;
+
+        if let Some(host) = self.auth_host()? {
+            let addr = lookup(&host).await?;
+            for official_host in AUTH_HOSTS {
+                builder = builder.resolve(official_host, addr);
+            }
+        }
+
+        if let Some(host) = self.storage_host()? {
+            let addr = lookup(&host).await?;
+            for official_host in STORAGE_HOSTS {
+                builder = builder.resolve(official_host, addr);
+            }
+        }
+
+        builder.build().context("Building reqwest client")
+    }
+
+    fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut builder =
+            reqwest::Client::builder().user_agent("marker-network-site-generator-cli");
+
+        if let Some(ca_cert) = &self.cloud_ca_cert {
+            let pem = std::fs::read(ca_cert).context("Reading cloud CA certificate")?;
+            let cert =
+                reqwest::Certificate::from_pem(&pem).context("Parsing cloud CA certificate")?;
+            builder = builder.add_root_certificate(cert);
+        }
+        Ok(builder)
+    }
+
+    /// The origin tokens are issued by, which tells credentials for one cloud
+    /// from those for another.
+    pub fn auth_origin(&self) -> Result<String> {
+        Ok(self.url(DEVICE_TOKEN_URL)?.origin().ascii_serialization())
+    }
+
+    /// Registers this tool as a new device, exchanging the one-time code from
+    /// the rM account's pairing page for a long lived device token.
+    pub async fn device_token(&self, http: &reqwest::Client, code: &str) -> Result<String> {
+        let token = http
+            .post(self.url(DEVICE_TOKEN_URL)?)
+            .json(&serde_json::json!({
+                "code": code,
+                "deviceDesc": "desktop-linux",
+                "deviceID": Uuid::new_v4().to_string(),
+            }))
+            .send()
+            .await
+            .context("Requesting device token")?
+            .error_for_status()
+            .context("rM cloud refused the one-time code")?
+            .text()
+            .await
+            .context("Reading device token")?;
+        if token.is_empty() {
+            return Err(anyhow!("rM cloud returned an empty device token"));
+        }
+        Ok(token)
+    }
+
+    /// Exchanges a device token for a short lived user token.
+    pub async fn user_token(&self, http: &reqwest::Client, device_token: &str) -> Result<String> {
+        let token = http
+            .post(self.url(USER_TOKEN_URL)?)
+            .bearer_auth(device_token)
+            .header(reqwest::header::CONTENT_LENGTH, 0)
+            .send()
+            .await
+            .context("Requesting user token")?
+            .error_for_status()
+            .context("rM cloud refused the device token")?
+            .text()
+            .await
+            .context("Reading user token")?;
+        Ok(token)
+    }
+}
+
+async fn lookup(host: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host((host, 443))
+        .await
+        .with_context(|| format!("Resolving cloud host {:?}", host))?
+        .next()
+        .ok_or_else(|| anyhow!("No addresses found for cloud host {:?}", host))
+}
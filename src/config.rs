@@ -3,6 +3,8 @@ use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::cloud::CloudEndpoints;
+use crate::raster::RasterFormat;
 use crate::theme::Theme;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -10,6 +12,28 @@ pub struct Config {
     pub site_root: String,
     pub title: String,
     pub theme: String,
+    /// Absolute url the site is published at, needed for feeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// `Posts` folders to hide from search engines, as `/` separated paths
+    /// such as `Drafts` or `Notes/Private`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub noindex: Vec<String>,
+    /// Export a PDF of every notebook and folder, and one of the whole site
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pdf: bool,
+    /// Widths in pixels to rasterize every page at, offered to themes as srcset's
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub raster_widths: Vec<u32>,
+    /// Formats pages are rasterized to, defaults to png
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub raster_formats: Vec<RasterFormat>,
+    /// Where rendered documents are cached between builds, defaults to the
+    /// user's cache directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<PathBuf>,
+    #[serde(flatten)]
+    pub cloud: CloudEndpoints,
 }
 
 impl Config {
@@ -33,8 +57,22 @@ impl Config {
         Ok(())
     }
 
+    /// The formats to rasterize pages to, empty if rasterizing is disabled.
+    pub fn raster_formats(&self) -> Vec<RasterFormat> {
+        if self.raster_widths.is_empty() {
+            Vec::new()
+        } else if self.raster_formats.is_empty() {
+            vec![RasterFormat::Png]
+        } else {
+            self.raster_formats.clone()
+        }
+    }
+
+    pub fn theme_dir(&self) -> PathBuf {
+        PathBuf::from("themes").join(&self.theme)
+    }
+
     pub fn theme(&self) -> Result<Theme> {
-        let theme_dir = PathBuf::from("themes").join(&self.theme);
-        Theme::load(&theme_dir)
+        Theme::load(&self.theme_dir())
     }
 }
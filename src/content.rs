@@ -0,0 +1,139 @@
+use std::io::{Read, Seek};
+
+use anyhow::{Context, Result};
+use remarkable_cloud_api::Uuid;
+use serde::Deserialize;
+
+/// The subset of a `<uuid>.content` file we care about, in either the old
+/// (`pages` + `redirectionPageMap`) or the new (`cPages`) layout.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawContent {
+    #[serde(default)]
+    file_type: String,
+    #[serde(default)]
+    pages: Vec<String>,
+    #[serde(default)]
+    redirection_page_map: Vec<i64>,
+    #[serde(default, rename = "cPages")]
+    c_pages: Option<RawCPages>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCPages {
+    #[serde(default)]
+    pages: Vec<RawCPage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCPage {
+    id: String,
+    idx: Option<Timestamped<String>>,
+    redir: Option<Timestamped<i64>>,
+    template: Option<Timestamped<String>>,
+    deleted: Option<Timestamped<i64>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Timestamped<T> {
+    value: T,
+}
+
+/// A page of a document in reading order.
+#[derive(Debug, Clone)]
+pub struct ContentPage {
+    pub id: String,
+    /// The page of the underlying PDF this page shows, `None` for pages
+    /// inserted by the user.
+    pub redirect: Option<usize>,
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Content {
+    pub file_type: String,
+    pub pages: Vec<ContentPage>,
+}
+
+impl Content {
+    pub fn from_reader<R: Read>(r: R) -> Result<Self> {
+        let raw: RawContent = serde_json::from_reader(r).context("Parsing .content file")?;
+        let is_background_doc = matches!(raw.file_type.as_str(), "pdf" | "epub");
+
+        let pages = match raw.c_pages {
+            Some(c_pages) if !c_pages.pages.is_empty() => {
+                let mut pages: Vec<RawCPage> = c_pages
+                    .pages
+                    .into_iter()
+                    .filter(|p| p.deleted.as_ref().map(|d| d.value) != Some(1))
+                    .collect();
+                // Pages are ordered by their fractional index, a sortable string
+                pages.sort_by(|a, b| {
+                    let a = a.idx.as_ref().map(|i| i.value.as_str());
+                    let b = b.idx.as_ref().map(|i| i.value.as_str());
+                    a.cmp(&b)
+                });
+                pages
+                    .into_iter()
+                    .map(|p| ContentPage {
+                        id: p.id,
+                        redirect: p
+                            .redir
+                            .and_then(|r| usize::try_from(r.value).ok())
+                            .filter(|_| is_background_doc),
+                        template: p.template.map(|t| t.value),
+                    })
+                    .collect()
+            }
+            _ => raw
+                .pages
+                .into_iter()
+                .enumerate()
+                .map(|(i, id)| {
+                    let redirect = match raw.redirection_page_map.get(i) {
+                        Some(r) => usize::try_from(*r).ok(),
+                        None => Some(i),
+                    };
+                    ContentPage {
+                        id,
+                        redirect: redirect.filter(|_| is_background_doc),
+                        template: None,
+                    }
+                })
+                .collect(),
+        };
+
+        Ok(Self {
+            file_type: raw.file_type,
+            pages,
+        })
+    }
+
+    /// Reads the `<uuid>.content` entry of a document zip, if it has one.
+    pub fn from_zip<R: Read + Seek>(
+        zip: &mut zip::ZipArchive<R>,
+        id: Uuid,
+    ) -> Result<Option<Self>> {
+        let file = match zip.by_name(&format!("{}.content", id)) {
+            Ok(file) => file,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e).context("Opening .content file in zip"),
+        };
+        Self::from_reader(file).map(Some)
+    }
+
+    /// Fills in the template of pages the .content doesn't name one for
+    /// from the lines of the older `.pagedata` file, which are in page order.
+    pub fn apply_pagedata(&mut self, templates: &[String]) {
+        for (page, template) in self.pages.iter_mut().zip(templates) {
+            if page.template.is_none() {
+                page.template = Some(template.clone());
+            }
+        }
+    }
+
+    /// Whether the document is an annotated PDF or EPUB rather than a notebook.
+    pub fn has_background(&self) -> bool {
+        matches!(self.file_type.as_str(), "pdf" | "epub")
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use remarkable_cloud_api::reqwest;
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::CloudEndpoints;
+
+/// Overrides the stored device token, for CI where there's no credentials file.
+pub const DEVICE_TOKEN_VAR: &str = "MARKER_DEVICE_TOKEN";
+
+/// Points at a credentials file other than the default one.
+pub const CREDENTIALS_PATH_VAR: &str = "MARKER_CREDENTIALS";
+
+/// User tokens this close to expiring are refreshed rather than used.
+const EXPIRY_MARGIN_SECS: i64 = 60;
+
+/// A user token along with when it stops being accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserToken {
+    pub token: String,
+    pub expires: DateTime<Utc>,
+}
+
+/// The device token `register` obtained, the cloud it was registered with
+/// and the last user token exchanged for it, stored in a file only the
+/// current user can read.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Credentials {
+    /// `CloudEndpoints::auth_origin` of the cloud the device token is for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cloud: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_token: Option<UserToken>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Credentials {
+    /// `$MARKER_CREDENTIALS`, or `credentials.json` in
+    /// `$XDG_CONFIG_HOME/marker-network-site-generator` (`~/.config` if unset).
+    pub fn default_path() -> Result<PathBuf> {
+        if let Some(path) = std::env::var_os(CREDENTIALS_PATH_VAR) {
+            return Ok(PathBuf::from(path));
+        }
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok_or_else(|| anyhow!("Neither XDG_CONFIG_HOME nor HOME is set"))?;
+        Ok(base
+            .join("marker-network-site-generator")
+            .join("credentials.json"))
+    }
+
+    /// Loads the credentials at `path`, empty if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut credentials = if path.exists() {
+            let file = std::fs::File::open(path).context("Opening credentials file")?;
+            serde_json::from_reader(file).context("Parsing credentials file")?
+        } else {
+            Self::default()
+        };
+        credentials.path = path.to_path_buf();
+        Ok(credentials)
+    }
+
+    /// Writes the credentials through a temp file created readable by the
+    /// current user only.
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).context("Creating credentials directory")?;
+        }
+        let temp_path = self.path.with_extension("json.tmp");
+        let _ = std::fs::remove_file(&temp_path);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&temp_path)
+            .context("Creating credentials file")?;
+
+        serde_json::to_writer_pretty(&mut file, self).context("Writing credentials file")?;
+        file.flush().context("Writing credentials file")?;
+
+        std::fs::rename(temp_path, &self.path).context("Renaming tempfile to credentials file")?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the device token with one registered with `cloud`, dropping
+    /// the user token issued for the old one.
+    pub fn set_device_token(&mut self, cloud: &CloudEndpoints, device_token: String) -> Result<()> {
+        self.cloud = Some(cloud.auth_origin()?);
+        self.device_token = Some(device_token);
+        self.user_token = None;
+        Ok(())
+    }
+
+    /// The device token from `$MARKER_DEVICE_TOKEN`, or the one registered
+    /// with `cloud`. A token registered with another cloud is refused.
+    pub fn device_token(&self, cloud: &CloudEndpoints) -> Result<String> {
+        if let Ok(token) = std::env::var(DEVICE_TOKEN_VAR) {
+            if !token.is_empty() {
+                return Ok(token);
+            }
+        }
+        let device_token = self.device_token.clone().ok_or_else(|| {
+            anyhow!(
+                "No device token, run `register <one-time-code>` or set {}",
+                DEVICE_TOKEN_VAR
+            )
+        })?;
+        let origin = cloud.auth_origin()?;
+        if self.cloud.as_deref() != Some(origin.as_str()) {
+            return Err(anyhow!(
+                "The stored device token was registered with {}, not {}, run `register <one-time-code>` for this cloud or set {}",
+                self.cloud.as_deref().unwrap_or("an unknown cloud"),
+                origin,
+                DEVICE_TOKEN_VAR
+            ));
+        }
+        Ok(device_token)
+    }
+
+    /// The cached user token while it's still valid, otherwise a new one
+    /// exchanged for the device token and saved for the next run.
+    pub async fn user_token(
+        &mut self,
+        cloud: &CloudEndpoints,
+        http: &reqwest::Client,
+    ) -> Result<String> {
+        let device_token = self.device_token(cloud)?;
+        let from_env = self.device_token.as_deref() != Some(device_token.as_str());
+
+        if let Some(cached) = &self.user_token {
+            let fresh = cached.expires - Duration::seconds(EXPIRY_MARGIN_SECS) > Utc::now();
+            if fresh && !from_env {
+                return Ok(cached.token.clone());
+            }
+        }
+
+        println!("Refreshing rM cloud user token");
+        let token = cloud.user_token(http, &device_token).await?;
+        // Tokens from a device token in the environment aren't cached, the
+        // stored credentials may belong to another account.
+        if !from_env {
+            self.user_token = Some(UserToken {
+                expires: token_expiry(&token).unwrap_or_else(|| Utc::now() + Duration::hours(1)),
+                token: token.clone(),
+            });
+            self.save().context("Caching user token")?;
+        }
+        Ok(token)
+    }
+}
+
+/// The `exp` claim of a JWT, if it has one.
+fn token_expiry(token: &str) -> Option<DateTime<Utc>> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: i64,
+    }
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    Utc.timestamp_opt(claims.exp, 0).single()
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::generator::render_entry;
+use crate::manifest::{DocumentMeta, Manifest, Posts};
+use crate::pdf::{self, PdfPage};
+use crate::render_cache::{self, RenderCache};
+
+/// Directory of an exported tree that holds the renders, laid out like the
+/// documents themselves. `init` doesn't upload it.
+pub const RENDERS_DIR: &str = "renders";
+
+/// Which renders to write for every document zip.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExportOptions {
+    /// `<name>.pdf` of the rendered pages
+    pub pdf: bool,
+    /// `<name>.svg`, or `<name> <n>.svg` per page for multi-page documents
+    pub svg: bool,
+}
+
+/// Writes the material at `material_path` to `out_dir` as a starter tree
+/// named after the documents: `Home.zip`, `Logo.zip` and `Posts/...`, with
+/// any renders at the same paths under `renders/`.
+///
+/// Names that would clash on disk, e.g. because they only differ in case,
+/// get a ` (2)`, ` (3)`, ... suffix. `out_dir` must be empty or missing.
+pub fn export(
+    material_path: &Path,
+    out_dir: &Path,
+    cache: &RenderCache,
+    options: ExportOptions,
+) -> Result<()> {
+    let manifest = Manifest::load(material_path).context("Loading manifest")?;
+    let exporter = Exporter {
+        zip_dir: material_path.join("zip"),
+        out_dir: out_dir.to_path_buf(),
+        cache,
+        options,
+    };
+
+    create_empty_dir(out_dir)?;
+
+    exporter.export_doc(&manifest.home, Path::new("Home"), false)?;
+    exporter.export_doc(&manifest.logo, Path::new("Logo"), true)?;
+    exporter.export_posts(&manifest.posts, Path::new("Posts"))
+}
+
+/// Creates `dir`, refusing to write into one that already has files in it.
+pub(crate) fn create_empty_dir(dir: &Path) -> Result<()> {
+    if dir.exists()
+        && std::fs::read_dir(dir)
+            .with_context(|| format!("Listing {:?}", dir))?
+            .next()
+            .is_some()
+    {
+        return Err(anyhow!("Output directory {:?} is not empty", dir));
+    }
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating {:?}", dir))
+}
+
+struct Exporter<'a> {
+    zip_dir: PathBuf,
+    out_dir: PathBuf,
+    cache: &'a RenderCache,
+    options: ExportOptions,
+}
+
+impl Exporter<'_> {
+    /// Exports `posts` to `dir`, relative to the output directory.
+    fn export_posts(&self, posts: &Posts, dir: &Path) -> Result<()> {
+        let out = self.out_dir.join(dir);
+        std::fs::create_dir_all(&out).with_context(|| format!("Creating {:?}", out))?;
+
+        let mut taken = BTreeSet::new();
+        for (name, doc) in posts.documents.iter() {
+            let base = dir.join(unique_name(&mut taken, name));
+            self.export_doc(doc, &base, false)?;
+        }
+
+        let mut taken = BTreeSet::new();
+        for (name, folder) in posts.folders.iter() {
+            let sub_dir = dir.join(unique_name(&mut taken, name));
+            self.export_posts(folder, &sub_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `<base>.zip` and whatever renders were asked for to
+    /// `renders/<base>.*`, `base` being relative to the output directory.
+    fn export_doc(&self, doc: &DocumentMeta, base: &Path, crop: bool) -> Result<()> {
+        let zip_path = self.zip_dir.join(format!("{}.zip", doc.id));
+        let out = with_extension(&self.out_dir.join(base), "zip");
+        println!("Exporting {:?}", out);
+        std::fs::copy(&zip_path, &out)
+            .with_context(|| format!("Copying zip of {:?} to {:?}", doc.name, out))?;
+
+        if !self.options.pdf && !self.options.svg {
+            return Ok(());
+        }
+        // A document that fails to render still has its zip exported.
+        let render_base = self.out_dir.join(RENDERS_DIR).join(base);
+        if let Err(e) = self.export_renders(doc, &zip_path, &render_base, crop) {
+            println!("Skipping renders of {:?}: {:#}", doc.name, e);
+        }
+        Ok(())
+    }
+
+    fn export_renders(
+        &self,
+        doc: &DocumentMeta,
+        zip_path: &Path,
+        base: &Path,
+        crop: bool,
+    ) -> Result<()> {
+        let entry = render_entry(self.cache, doc, zip_path, crop)?;
+        if let Some(dir) = base.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Creating {:?}", dir))?;
+        }
+
+        if self.options.pdf {
+            let file = std::fs::File::open(entry.join("pdf.json")).context("Opening pdf pages")?;
+            let pages: Vec<PdfPage> = serde_json::from_reader(file).context("Parsing pdf pages")?;
+            if pages.iter().any(|page| page.background) {
+                println!(
+                    "Skipping the pdf of {:?}, the pages of its PDF or EPUB can't be included yet",
+                    doc.name
+                );
+            } else {
+                pdf::write_pdf(&with_extension(base, "pdf"), &pages, &[])
+                    .context("Writing document pdf")?;
+            }
+        }
+
+        if self.options.svg {
+            let svgs = render_cache::entry_svgs(&entry)?;
+            for (i, svg) in svgs.iter().enumerate() {
+                let out = if svgs.len() == 1 {
+                    with_extension(base, "svg")
+                } else {
+                    let name = base.file_name().unwrap_or_default().to_string_lossy();
+                    base.with_file_name(format!("{} {}.svg", name, i + 1))
+                };
+                std::fs::copy(svg, &out).with_context(|| format!("Copying {:?}", out))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends `.<extension>` to `base`, which may contain dots of its own.
+fn with_extension(base: &Path, extension: &str) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// A file name for `name` not yet in `taken`, compared case-insensitively
+/// since not every file system tells `Notes` and `notes` apart.
+fn unique_name(taken: &mut BTreeSet<String>, name: &str) -> String {
+    let base = name.replace('/', "-");
+    let base = if base.is_empty() || base == "." || base == ".." {
+        "Untitled".to_string()
+    } else {
+        base
+    };
+
+    let mut candidate = base.clone();
+    let mut n = 2;
+    while !taken.insert(candidate.to_lowercase()) {
+        candidate = format!("{} ({})", base, n);
+        n += 1;
+    }
+    candidate
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use remarkable_cloud_api::Uuid;
+
+use crate::init::{sync_directory, SyncOptions};
+use crate::manifest::{Node, NodeTree};
+use crate::source::{CloudBackend, MaterialSource};
+
+/// An in-memory stand-in for the rM cloud, for running `init`, `fetch` and
+/// `gen` end to end without credentials. Only built with the `fake-cloud`
+/// feature, which the tests turn on.
+#[derive(Debug, Default)]
+pub struct FakeCloud {
+    state: Mutex<FakeCloudState>,
+}
+
+#[derive(Debug, Default)]
+struct FakeCloudState {
+    /// Every folder and document along with its parent
+    nodes: BTreeMap<Uuid, (Option<Uuid>, Node)>,
+    zips: BTreeMap<Uuid, Vec<u8>>,
+    downloads: usize,
+}
+
+impl FakeCloud {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a fake cloud holding a single top level folder named
+    /// `folder_name` with the contents of a starter tree such as `starters/blog/`.
+    pub async fn seeded(starter: &Path, folder_name: &str) -> Result<Self> {
+        let cloud = Self::new();
+        let folder_id = cloud
+            .create_folder(Uuid::new_v4(), folder_name.to_string(), None)
+            .await?;
+        let options = SyncOptions {
+            dry_run: false,
+            replace: false,
+            site_root: true,
+        };
+        sync_directory(
+            &cloud,
+            &NodeTree::default(),
+            starter,
+            Some(folder_id),
+            options,
+            0,
+        )
+        .await
+        .context("Seeding fake cloud")?;
+        Ok(cloud)
+    }
+
+    /// How many document zips have been downloaded so far.
+    pub fn downloads(&self) -> usize {
+        self.state.lock().map_or(0, |state| state.downloads)
+    }
+
+    fn insert(&self, parent: Option<Uuid>, node: Node, zip: Option<Vec<u8>>) -> Result<Uuid> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("Fake cloud state poisoned"))?;
+        if let Some(parent) = parent {
+            match state.nodes.get(&parent) {
+                Some((_, p)) if p.doc_type == "CollectionType" => (),
+                _ => return Err(anyhow!("No folder with ID {}", parent)),
+            }
+        }
+        let id = node.id;
+        state.nodes.insert(id, (parent, node));
+        if let Some(zip) = zip {
+            state.zips.insert(id, zip);
+        }
+        Ok(id)
+    }
+}
+
+#[async_trait]
+impl MaterialSource for FakeCloud {
+    async fn list_documents(&self) -> Result<NodeTree> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("Fake cloud state poisoned"))?;
+        let mut nodes = NodeTree::default();
+        for (parent, node) in state.nodes.values() {
+            nodes.insert(*parent, node.clone());
+        }
+        Ok(nodes)
+    }
+
+    async fn download_zip(&self, id: Uuid) -> Result<Vec<u8>> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("Fake cloud state poisoned"))?;
+        state.downloads += 1;
+        state
+            .zips
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| anyhow!("No document with ID {}", id))
+    }
+}
+
+#[async_trait]
+impl CloudBackend for FakeCloud {
+    async fn create_folder(&self, id: Uuid, name: String, parent: Option<Uuid>) -> Result<Uuid> {
+        let node = Node {
+            id,
+            visible_name: name,
+            doc_type: "CollectionType".to_string(),
+            modified_client: chrono::Utc::now(),
+        };
+        self.insert(parent, node, None)
+    }
+
+    async fn upload_notebook(
+        &self,
+        id: Uuid,
+        name: String,
+        parent: Option<Uuid>,
+        zip: Vec<u8>,
+    ) -> Result<Uuid> {
+        let zip = rename_entries(&zip, id).context("Reading ZipArchive")?;
+        let node = Node {
+            id,
+            visible_name: name,
+            doc_type: "DocumentType".to_string(),
+            modified_client: chrono::Utc::now(),
+        };
+        self.insert(parent, node, Some(zip))
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<()> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("Fake cloud state poisoned"))?;
+        match state.nodes.get(&id) {
+            Some((_, node)) if node.doc_type == "DocumentType" => (),
+            _ => return Err(anyhow!("No document with ID {}", id)),
+        }
+        state.nodes.remove(&id);
+        state.zips.remove(&id);
+        Ok(())
+    }
+}
+
+/// Renames the files of a document zip to be under `id`, the way the rM
+/// cloud hands them out, whatever ID the zip was exported with.
+fn rename_entries(bytes: &[u8], id: Uuid) -> Result<Vec<u8>> {
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(bytes))?;
+    let mut renamed = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        let name = file.name().to_string();
+        let rest = name.find(['.', '/']).map_or("", |split| &name[split..]);
+        renamed.raw_copy_file_rename(file, format!("{}{}", id, rest))?;
+    }
+    Ok(renamed.finish()?.into_inner())
+}
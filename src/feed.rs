@@ -0,0 +1,173 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+/// A post as it appears in the site's feeds, with absolute urls.
+#[derive(Debug, Clone)]
+pub struct FeedEntry {
+    pub title: String,
+    pub link: String,
+    pub image: String,
+    pub updated: chrono::DateTime<chrono::Utc>,
+}
+
+/// Site wide information shared by every feed format.
+pub struct Feed<'a> {
+    pub title: &'a str,
+    pub site_url: &'a str,
+    /// Sorted newest first
+    pub entries: &'a [FeedEntry],
+}
+
+impl Feed<'_> {
+    fn updated(&self) -> chrono::DateTime<chrono::Utc> {
+        self.entries
+            .first()
+            .map(|e| e.updated)
+            .unwrap_or_else(chrono::Utc::now)
+    }
+
+    fn feed_url(&self, file_name: &str) -> String {
+        format!("{}/{}", self.site_url.trim_end_matches('/'), file_name)
+    }
+
+    pub fn write_all(&self, gen_root: &Path) -> Result<()> {
+        self.write_atom(&gen_root.join("feed.xml"))
+            .context("Writing atom feed")?;
+        self.write_rss(&gen_root.join("rss.xml"))
+            .context("Writing rss feed")?;
+        self.write_json(&gen_root.join("feed.json"))
+            .context("Writing json feed")?;
+        Ok(())
+    }
+
+    fn write_atom(&self, path: &Path) -> Result<()> {
+        let mut out = std::fs::File::create(path).context("Creating feed.xml")?;
+        writeln!(out, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writeln!(out, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#)?;
+        writeln!(out, "  <title>{}</title>", escape_xml(self.title))?;
+        writeln!(out, "  <id>{}</id>", escape_xml(self.site_url))?;
+        writeln!(out, r#"  <link href="{}"/>"#, escape_xml(self.site_url))?;
+        writeln!(
+            out,
+            r#"  <link rel="self" href="{}"/>"#,
+            escape_xml(&self.feed_url("feed.xml"))
+        )?;
+        writeln!(out, "  <updated>{}</updated>", self.updated().to_rfc3339())?;
+        for entry in self.entries {
+            writeln!(out, "  <entry>")?;
+            writeln!(out, "    <title>{}</title>", escape_xml(&entry.title))?;
+            writeln!(out, "    <id>{}</id>", escape_xml(&entry.link))?;
+            writeln!(out, r#"    <link href="{}"/>"#, escape_xml(&entry.link))?;
+            writeln!(out, "    <updated>{}</updated>", entry.updated.to_rfc3339())?;
+            writeln!(
+                out,
+                "    <author><name>{}</name></author>",
+                escape_xml(self.title)
+            )?;
+            writeln!(
+                out,
+                r#"    <content type="html">{}</content>"#,
+                escape_xml(&entry.content_html())
+            )?;
+            writeln!(out, "  </entry>")?;
+        }
+        writeln!(out, "</feed>")?;
+        Ok(())
+    }
+
+    fn write_rss(&self, path: &Path) -> Result<()> {
+        let mut out = std::fs::File::create(path).context("Creating rss.xml")?;
+        writeln!(out, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writeln!(
+            out,
+            r#"<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom">"#
+        )?;
+        writeln!(out, "  <channel>")?;
+        writeln!(out, "    <title>{}</title>", escape_xml(self.title))?;
+        writeln!(out, "    <link>{}</link>", escape_xml(self.site_url))?;
+        writeln!(
+            out,
+            "    <description>{}</description>",
+            escape_xml(self.title)
+        )?;
+        writeln!(
+            out,
+            r#"    <atom:link href="{}" rel="self" type="application/rss+xml"/>"#,
+            escape_xml(&self.feed_url("rss.xml"))
+        )?;
+        writeln!(
+            out,
+            "    <lastBuildDate>{}</lastBuildDate>",
+            self.updated().to_rfc2822()
+        )?;
+        for entry in self.entries {
+            writeln!(out, "    <item>")?;
+            writeln!(out, "      <title>{}</title>", escape_xml(&entry.title))?;
+            writeln!(out, "      <link>{}</link>", escape_xml(&entry.link))?;
+            writeln!(out, "      <guid>{}</guid>", escape_xml(&entry.link))?;
+            writeln!(
+                out,
+                "      <pubDate>{}</pubDate>",
+                entry.updated.to_rfc2822()
+            )?;
+            writeln!(
+                out,
+                "      <description>{}</description>",
+                escape_xml(&entry.content_html())
+            )?;
+            writeln!(out, "    </item>")?;
+        }
+        writeln!(out, "  </channel>")?;
+        writeln!(out, "</rss>")?;
+        Ok(())
+    }
+
+    fn write_json(&self, path: &Path) -> Result<()> {
+        let feed = json!({
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": self.title,
+            "home_page_url": self.site_url,
+            "feed_url": self.feed_url("feed.json"),
+            "items": self.entries.iter().map(|entry| json!({
+                "id": entry.link,
+                "url": entry.link,
+                "title": entry.title,
+                "image": entry.image,
+                "content_html": entry.content_html(),
+                "date_modified": entry.updated.to_rfc3339(),
+            })).collect::<Vec<_>>(),
+        });
+        let out = std::fs::File::create(path).context("Creating feed.json")?;
+        serde_json::to_writer_pretty(out, &feed).context("Writing feed.json")?;
+        Ok(())
+    }
+}
+
+impl FeedEntry {
+    fn content_html(&self) -> String {
+        format!(
+            r#"<a href="{}"><img src="{}" alt="{}"/></a>"#,
+            escape_xml(&self.link),
+            escape_xml(&self.image),
+            escape_xml(&self.title)
+        )
+    }
+}
+
+pub fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures::stream::{self, StreamExt};
+use remarkable_cloud_api::{reqwest, Uuid};
+
+use crate::config::Config;
+use crate::manifest::{DocumentMeta, Manifest};
+use crate::source::MaterialSource;
+
+/// Delay before the first retry of a failed download, doubled on every
+/// further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How hard `fetch` pushes the source.
+#[derive(Debug, Clone, Copy)]
+pub struct FetchOptions {
+    /// Documents downloaded at the same time.
+    pub jobs: usize,
+    /// Retries of a download that failed with a transient error.
+    pub retries: u32,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 8,
+            retries: 4,
+        }
+    }
+}
+
+/// Downloads every site document that changed since the last fetch into
+/// `zip/<uuid>.zip`, then saves the manifest next to them.
+///
+/// A failed download doesn't stop the others. Documents that did download
+/// are kept, the failed ones are listed and fetched again next time.
+pub async fn fetch(
+    config: Config,
+    source: &impl MaterialSource,
+    output_path: &Path,
+    options: FetchOptions,
+) -> Result<()> {
+    std::fs::create_dir_all(output_path).context("Creating material output directory")?;
+
+    let archives_dir = output_path.join("zip");
+    std::fs::create_dir_all(&archives_dir).context("Creating zip archives directory")?;
+
+    let documents = source
+        .list_documents()
+        .await
+        .context("Fetching all document metadata")?;
+
+    let existing_docs: BTreeMap<Uuid, DocumentMeta> =
+        if let Ok(existing_manifest) = Manifest::load(output_path).context("Loading manifest") {
+            existing_manifest
+                .docs()
+                .into_iter()
+                .map(|d| (d.id, d.clone()))
+                .collect()
+        } else {
+            Default::default()
+        };
+
+    let mut manifest = Manifest::build(config.site_root, &documents)
+        .context("Building Manifest from documents")?;
+
+    let mut changed = Vec::new();
+    for doc in manifest.docs() {
+        let zip_path = archives_dir.join(format!("{}.zip", doc.id));
+        if let Some(existing_doc) = existing_docs.get(&doc.id) {
+            if existing_doc.modified_client >= doc.modified_client && is_valid_zip(&zip_path) {
+                println!("Nothing new from {}", doc.id);
+                continue;
+            }
+        }
+        changed.push((doc.clone(), zip_path));
+    }
+
+    let total = changed.len();
+    let failures: BTreeMap<Uuid, (String, anyhow::Error)> = stream::iter(changed)
+        .map(|(doc, zip_path)| async move {
+            let result = download_with_retries(source, &doc, options.retries)
+                .await
+                .and_then(|bytes| {
+                    write_zip(&zip_path, &bytes)
+                        .with_context(|| format!("Saving document zip of {:?}", doc.name))
+                });
+            (doc, result)
+        })
+        .buffer_unordered(options.jobs.max(1))
+        .filter_map(|(doc, result)| async move {
+            match result {
+                Ok(()) => {
+                    println!("Downloaded {:?}", doc.name);
+                    None
+                }
+                Err(e) => Some((doc.id, (doc.name, e))),
+            }
+        })
+        .collect()
+        .await;
+
+    // Keep the old timestamp of documents that failed so the next fetch
+    // picks them up again instead of trusting whatever zip is on disk.
+    for doc in manifest.docs_mut() {
+        if failures.contains_key(&doc.id) {
+            doc.modified_client = existing_docs
+                .get(&doc.id)
+                .map(|existing| existing.modified_client)
+                .unwrap_or_else(|| std::time::UNIX_EPOCH.into());
+        }
+    }
+
+    manifest
+        .save(output_path)
+        .context("Saving the generated Manifest")?;
+
+    gc_zips(&archives_dir, &manifest).context("Removing stale zips")?;
+
+    if !failures.is_empty() {
+        println!(
+            "Failed to download {} of {} documents:",
+            failures.len(),
+            total
+        );
+        for (id, (name, e)) in failures.iter() {
+            println!("  {:?} ({}): {:#}", name, id, e);
+        }
+        return Err(anyhow!("{} documents failed to download", failures.len()));
+    }
+
+    Ok(())
+}
+
+async fn download_with_retries(
+    source: &impl MaterialSource,
+    doc: &DocumentMeta,
+    retries: u32,
+) -> Result<Vec<u8>> {
+    let mut attempt = 0;
+    loop {
+        println!("Downloading {}", doc.id);
+        let result = source
+            .download_zip(doc.id)
+            .await
+            .context("Downloading document zip")
+            .and_then(|bytes| {
+                zip::ZipArchive::new(std::io::Cursor::new(&bytes))
+                    .context("Downloaded zip is corrupt")?;
+                Ok(bytes)
+            });
+        match result {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = RETRY_BASE_DELAY
+                    .saturating_mul(2u32.saturating_pow(attempt))
+                    .min(RETRY_MAX_DELAY);
+                println!(
+                    "Retrying {:?} in {:?} after error: {:#}",
+                    doc.name, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Only timeouts, failed connections and server errors are worth
+/// retrying, anything else would fail the same way again.
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|e| {
+            e.is_timeout()
+                || e.is_connect()
+                || e.status().is_some_and(|status| status.is_server_error())
+        })
+}
+
+fn is_valid_zip(path: &Path) -> bool {
+    std::fs::File::open(path)
+        .map(|file| zip::ZipArchive::new(file).is_ok())
+        .unwrap_or(false)
+}
+
+/// Checks a downloaded zip is readable, then writes it through a temp file
+/// so an interrupted fetch never leaves a truncated zip behind.
+pub(crate) fn write_zip(path: &Path, bytes: &[u8]) -> Result<()> {
+    zip::ZipArchive::new(std::io::Cursor::new(bytes)).context("Downloaded zip is corrupt")?;
+
+    let temp_path = path.with_extension("zip.tmp");
+    let mut file = std::fs::File::create(&temp_path).context("Creating file for document zip")?;
+    file.write_all(bytes)
+        .context("Writing document zip to disk")?;
+    file.sync_all().context("Syncing document zip")?;
+
+    std::fs::rename(temp_path, path).context("Renaming tempfile to document zip")?;
+    Ok(())
+}
+
+/// Removes zips of documents no longer in the site, and temp files left by
+/// interrupted fetches.
+pub(crate) fn gc_zips(archives_dir: &Path, manifest: &Manifest) -> Result<()> {
+    let keep: BTreeSet<String> = manifest
+        .docs()
+        .iter()
+        .map(|doc| format!("{}.zip", doc.id))
+        .collect();
+    for entry in std::fs::read_dir(archives_dir).context("Listing zip archives directory")? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_file() && !keep.contains(name.as_ref()) {
+            println!("Removing stale {:?}", path);
+            std::fs::remove_file(&path).with_context(|| format!("Removing {:?}", path))?;
+        }
+    }
+    Ok(())
+}
@@ -1,72 +1,33 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use rayon::prelude::*;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use remarkable_cloud_api::Uuid;
-use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+use crate::background::{self, PdfBackground};
 use crate::config::Config;
-use crate::manifest::{Manifest, Posts};
+use crate::content::Content;
+use crate::feed::{Feed, FeedEntry};
+use crate::manifest::{DocumentMeta, Manifest, Posts};
+use crate::pdf::{self, Bookmark, PdfPage};
+use crate::prune;
+use crate::raster::{RasterFormat, Rasterizer};
+use crate::render_cache::{self, RenderCache, RenderSettings};
+use crate::rm6;
+use crate::sitemap::{self, SitemapEntry};
 use crate::theme::Theme;
 
-const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RenderCache {
-    version: String,
-    cache: BTreeMap<Uuid, chrono::DateTime<chrono::Utc>>,
-}
-
-impl Default for RenderCache {
-    fn default() -> Self {
-        Self {
-            version: CRATE_VERSION.to_string(),
-            cache: Default::default(),
-        }
-    }
-}
-
-impl RenderCache {
-    fn get(&self, id: &Uuid) -> Option<&chrono::DateTime<chrono::Utc>> {
-        self.cache.get(id)
-    }
-
-    fn is_current_version(&self) -> bool {
-        self.version == CRATE_VERSION
-    }
-
-    fn load(build_root: &Path) -> Result<Self> {
-        let render_cache_path = &build_root.join("render_cache.json");
-        if render_cache_path.exists() {
-            let render_cache_file =
-                std::fs::File::open(render_cache_path).context("Opening render cache file")?;
-            let render_cache: Self =
-                serde_json::from_reader(render_cache_file).context("Parsing render_cache file")?;
-            if render_cache.is_current_version() {
-                Ok(render_cache)
-            } else {
-                Ok(Self::default())
-            }
-        } else {
-            Ok(Self::default())
-        }
-    }
-
-    fn save(&self, build_root: &Path) -> Result<()> {
-        let render_cache_file = std::fs::File::create(&build_root.join("render_cache.json"))
-            .context("Creating render cache file")?;
-        serde_json::to_writer_pretty(render_cache_file, &self)
-            .context("Writing render cache json")?;
-        Ok(())
-    }
-}
+/// Scale handed to the svg renderer.
+const RENDER_SCALE: f32 = 2.0;
 
 pub struct Generator {
     root: PathBuf,
+    material_path: PathBuf,
     prefix: PathBuf,
     config: Config,
     manifest: Manifest,
@@ -74,6 +35,13 @@ pub struct Generator {
     svgs: BTreeMap<Uuid, Vec<PathBuf>>, // Rendered notebook pages
     build_nonce: String,
     render_cache: RenderCache,
+    /// Files linked in from the render cache by the last render
+    rendered_outputs: Mutex<BTreeSet<PathBuf>>,
+    /// The render cache entry of every document, PDF pages are read from
+    /// there rather than published
+    entries: Mutex<BTreeMap<Uuid, PathBuf>>,
+    /// Files written by the last `gen_index`
+    page_outputs: Mutex<BTreeSet<PathBuf>>,
 }
 
 impl Generator {
@@ -89,30 +57,71 @@ impl Generator {
         let manifest = Manifest::load(&material_path).context("Loading manifest")?;
         println!("Loaded manifest {:#?}", manifest);
 
-        let render_cache = if no_cache {
-            RenderCache::default()
-        } else {
-            RenderCache::load(&root)?
-        };
+        let cache_dir = config
+            .cache_dir
+            .clone()
+            .unwrap_or_else(RenderCache::default_dir);
+        let render_cache = RenderCache::open(cache_dir, no_cache)?;
 
         let theme = config.theme().context("Loading theme from config")?;
         let mut gen = Self {
             root,
+            material_path,
             prefix,
             config,
             manifest,
             theme,
             svgs: Default::default(),
-            build_nonce: chrono::Utc::now().format("%Y-%m-%dT%H-%M-%S").to_string(),
+            build_nonce: new_build_nonce(),
             render_cache,
+            rendered_outputs: Default::default(),
+            entries: Default::default(),
+            page_outputs: Default::default(),
         };
-        gen.svgs = gen
-            .render_all_svgs(&material_path)
-            .context("Rendering svg's")?;
+        gen.svgs = gen.render_all_svgs().context("Rendering svg's")?;
 
         Ok(gen)
     }
 
+    pub fn material_path(&self) -> &Path {
+        &self.material_path
+    }
+
+    pub fn theme_dir(&self) -> PathBuf {
+        self.config.theme_dir()
+    }
+
+    /// Reloads the theme templates without touching any rendered svg's.
+    pub fn reload_theme(&mut self) -> Result<()> {
+        self.theme = self.config.theme().context("Reloading theme")?;
+        self.build_nonce = new_build_nonce();
+        Ok(())
+    }
+
+    /// Reloads the manifest and re-renders the given documents, every other
+    /// document is served from the render cache.
+    pub fn reload_material(&mut self, changed: &BTreeSet<Uuid>) -> Result<()> {
+        self.manifest = Manifest::load(&self.material_path).context("Reloading manifest")?;
+        // Changed zips hash to new cache keys, so only they get re-rendered
+        println!("Re-rendering {} changed documents", changed.len());
+        self.svgs = self.render_all_svgs().context("Re-rendering svg's")?;
+        self.build_nonce = new_build_nonce();
+        Ok(())
+    }
+
+    /// Removes everything in the build directory that earlier builds produced
+    /// but the last one didn't. With `dry_run` the files are only listed.
+    pub fn prune(&self, dry_run: bool) -> Result<Vec<PathBuf>> {
+        let mut outputs = lock(&self.rendered_outputs)?.clone();
+        outputs.extend(lock(&self.page_outputs)?.iter().cloned());
+        prune::prune(&self.root, &outputs, dry_run)
+    }
+
+    fn record_page_output(&self, path: &Path) -> Result<()> {
+        lock(&self.page_outputs)?.insert(path.to_path_buf());
+        Ok(())
+    }
+
     fn has_content(&self, id: Uuid) -> bool {
         !self.doc_pages(id).is_empty()
     }
@@ -138,6 +147,243 @@ impl Generator {
         &self.svgs[&id]
     }
 
+    /// Link to the page of a post or folder given its path through the
+    /// `Posts` folders.
+    fn page_link(&self, path: &[String]) -> PathBuf {
+        let (name, folders) = path.split_last().expect("Page paths are never empty");
+        let mut link = self.prefix.join("posts");
+        for folder in folders {
+            link.push(sanitize(folder));
+        }
+        link.join(format!("{}.html", sanitize(name)))
+    }
+
+    /// The image to show for a document where svg isn't accepted, the largest
+    /// png rasterization of its first page if there is one.
+    fn preview_image(&self, id: Uuid) -> PathBuf {
+        let first_page = self.doc_first_page(id);
+        if self.config.raster_formats().contains(&RasterFormat::Png) {
+            if let Some(width) = self.config.raster_widths.iter().max() {
+                if let Ok(link) = self.relative_to_root(&self.raster_path(
+                    id,
+                    first_page,
+                    *width,
+                    RasterFormat::Png,
+                )) {
+                    return link;
+                }
+            }
+        }
+        first_page.to_path_buf()
+    }
+
+    fn base_url(&self) -> Result<Option<url::Url>> {
+        match &self.config.base_url {
+            Some(base_url) => {
+                let mut url = url::Url::parse(base_url).context("Parsing base_url from config")?;
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Ok(Some(url))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Turns a link produced by the generator into an absolute url.
+    fn absolute_url(&self, base_url: &url::Url, link: &Path) -> Result<String> {
+        let relative = link.strip_prefix(&self.prefix).unwrap_or(link);
+        let relative = relative
+            .to_str()
+            .ok_or_else(|| anyhow!("Link is not valid utf8: {:?}", link))?;
+        let url = base_url
+            .join(relative)
+            .with_context(|| format!("Joining {:?} onto base_url", relative))?;
+        Ok(url.to_string())
+    }
+
+    /// Whether a folder, given its path through the `Posts` folders, or one
+    /// of its parents opted out of search engine indexing.
+    fn is_noindex(&self, folders: &[String]) -> bool {
+        (1..=folders.len()).any(|n| self.config.noindex.contains(&folders[..n].join("/")))
+    }
+
+    fn raster_path(&self, id: Uuid, page: &Path, width: u32, format: RasterFormat) -> PathBuf {
+        let page_name = page.file_stem().unwrap_or_default().to_string_lossy();
+        self.root
+            .join("raster")
+            .join(format!("{}", id))
+            .join(format!("{}-{}.{}", page_name, width, format.extension()))
+    }
+
+    /// Rasterizes the svg's of a render cache entry at every configured
+    /// width, skipping images the entry already has.
+    fn rasterize_entry(&self, entry: &Path) -> Result<()> {
+        let formats = self.config.raster_formats();
+        if formats.is_empty() {
+            return Ok(());
+        }
+        let raster_dir = entry.join("raster");
+        std::fs::create_dir_all(&raster_dir).context("Creating raster directory")?;
+
+        for svg_path in render_cache::entry_svgs(entry)? {
+            let page_name = svg_path.file_stem().unwrap_or_default().to_string_lossy();
+            let mut rasterizer = None;
+            for format in formats.iter() {
+                for width in self.config.raster_widths.iter() {
+                    let out =
+                        raster_dir.join(format!("{}-{}.{}", page_name, width, format.extension()));
+                    if out.metadata().map(|m| m.len() > 0).unwrap_or(false) {
+                        continue;
+                    }
+                    if rasterizer.is_none() {
+                        rasterizer = Some(Rasterizer::load(&svg_path)?);
+                    }
+                    println!("Rasterizing {:?} at {}px", svg_path, width);
+                    let temp = out.with_extension("tmp");
+                    rasterizer
+                        .as_ref()
+                        .unwrap()
+                        .render(*width, *format, &temp)
+                        .context("Rasterizing page")?;
+                    std::fs::rename(&temp, &out).context("Moving rasterized page into place")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A page as handed to templates: its svg and a srcset per raster format.
+    fn page_image(&self, id: Uuid, page: &Path) -> Result<serde_json::Value> {
+        let mut image = json!({ "svg": page });
+        for format in self.config.raster_formats() {
+            let srcset = self
+                .config
+                .raster_widths
+                .iter()
+                .map(|width| {
+                    let link =
+                        self.relative_to_root(&self.raster_path(id, page, *width, format))?;
+                    Ok(format!("{} {}w", link.display(), width))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            image[format.extension()] = json!(srcset.join(", "));
+        }
+        Ok(image)
+    }
+
+    fn doc_images(&self, id: Uuid) -> Result<Vec<serde_json::Value>> {
+        self.doc_pages(id)
+            .iter()
+            .map(|page| self.page_image(id, page))
+            .collect()
+    }
+
+    /// The PDF pages of a rendered notebook, kept in its render cache entry.
+    /// `None` for an annotated PDF or EPUB, whose pages would come out as
+    /// strokes on blank paper.
+    fn doc_pdf_pages(&self, id: Uuid) -> Result<Option<Vec<PdfPage>>> {
+        let entry = lock(&self.entries)?
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Document {} was not rendered", id))?;
+        let file = std::fs::File::open(entry.join("pdf.json")).context("Opening pdf pages")?;
+        let pages: Vec<PdfPage> = serde_json::from_reader(file).context("Parsing pdf pages")?;
+        if pages.iter().any(|page| page.background) {
+            println!(
+                "Leaving {} out of the pdfs, the pages of its PDF or EPUB can't be included yet",
+                id
+            );
+            return Ok(None);
+        }
+        Ok(Some(pages))
+    }
+
+    /// Collects the pages of every document in `posts`, followed by those of
+    /// its sub-folders, bookmarking each document and folder.
+    fn pdf_book(&self, name: &str, posts: &Posts) -> Result<(Vec<PdfPage>, Bookmark)> {
+        let mut pages = Vec::new();
+        let mut bookmark = Bookmark {
+            title: name.to_string(),
+            page: 0,
+            children: Vec::new(),
+        };
+        self.append_book(posts, &mut pages, &mut bookmark.children)?;
+        Ok((pages, bookmark))
+    }
+
+    fn append_book(
+        &self,
+        posts: &Posts,
+        pages: &mut Vec<PdfPage>,
+        bookmarks: &mut Vec<Bookmark>,
+    ) -> Result<()> {
+        for doc in posts.documents.values() {
+            if !self.has_content(doc.id) {
+                continue;
+            }
+            let doc_pages = match self.doc_pdf_pages(doc.id)? {
+                Some(doc_pages) => doc_pages,
+                None => continue,
+            };
+            bookmarks.push(Bookmark {
+                title: doc.name.clone(),
+                page: pages.len(),
+                children: Vec::new(),
+            });
+            pages.extend(doc_pages);
+        }
+
+        for (sub_folder_name, sub_folder_posts) in posts.folders.iter() {
+            let mut bookmark = Bookmark {
+                title: sub_folder_name.clone(),
+                page: pages.len(),
+                children: Vec::new(),
+            };
+            self.append_book(sub_folder_posts, pages, &mut bookmark.children)?;
+            if !bookmark.children.is_empty() {
+                bookmarks.push(bookmark);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the whole site as one PDF, the home page followed by every post.
+    fn gen_site_pdf(&self) -> Result<Option<PathBuf>> {
+        if !self.config.pdf {
+            return Ok(None);
+        }
+
+        let mut pages = Vec::new();
+        let mut bookmarks = Vec::new();
+        if let Some(home_pages) = self.doc_pdf_pages(self.manifest.home.id)? {
+            pages = home_pages;
+            bookmarks.push(Bookmark {
+                title: "Home".to_string(),
+                page: 0,
+                children: Vec::new(),
+            });
+        }
+        let (posts_pages, mut posts_bookmark) = self.pdf_book("Posts", &self.manifest.posts)?;
+        if !posts_pages.is_empty() {
+            posts_bookmark.page = pages.len();
+            for bookmark in posts_bookmark.children.iter_mut() {
+                offset_bookmark(bookmark, pages.len());
+            }
+            pages.extend(posts_pages);
+            bookmarks.push(posts_bookmark);
+        }
+        if pages.is_empty() {
+            return Ok(None);
+        }
+
+        let pdf_path = self.root.join("site.pdf");
+        println!("Writing site pdf");
+        pdf::write_pdf(&pdf_path, &pages, &bookmarks).context("Writing site pdf")?;
+        self.record_page_output(&pdf_path)?;
+        Ok(Some(self.relative_to_root(&pdf_path)?))
+    }
+
     fn relative_to_root(&self, path: &Path) -> Result<PathBuf> {
         Ok(self.prefix.join(
             path.strip_prefix(&self.root)
@@ -146,6 +392,8 @@ impl Generator {
     }
 
     pub fn gen_index(&self) -> Result<()> {
+        lock(&self.page_outputs)?.clear();
+
         let mut docs: Vec<(String, Uuid, PathBuf)> = Vec::new();
         let mut sub_folders: Vec<(String, PathBuf)> = Vec::new();
 
@@ -153,7 +401,9 @@ impl Generator {
         std::fs::create_dir_all(&posts_path)
             .context("Creating posts directory in generated site root")?;
 
-        let breadcrumbs = &[("Home".to_string(), self.prefix.clone())];
+        let breadcrumbs = &[("Home".to_string(), self.prefix.join("index.html"))];
+
+        let site_pdf = self.gen_site_pdf().context("Generating site pdf")?;
 
         for doc in self.manifest.posts.documents.values() {
             let doc_path = self
@@ -178,12 +428,16 @@ impl Generator {
                     "logo": self.logo_svg(),
                     "name": "Home",
                     "pages": self.home_pages(),
+                    "images": self.doc_images(self.manifest.home.id)?,
                     "render_nav_thumbnails": self.home_pages().len() > 1,
-                    "documents": docs.into_iter().map(|(name, id, link)| json!({
+                    "feeds": self.config.base_url.is_some(),
+                    "pdf": site_pdf,
+                    "documents": docs.into_iter().filter(|(_, id, _)| self.has_content(*id)).map(|(name, id, link)| Ok(json!({
                         "name": name,
                         "svg": self.doc_first_page(id),
+                        "thumbnail": self.page_image(id, self.doc_first_page(id))?,
                         "link": link,
-                    })).collect::<Vec<_>>(),
+                    }))).collect::<Result<Vec<_>>>()?,
                     "folders": sub_folders.into_iter().map(|(name, link)| json!({
                         "name": name,
                         "link": link,
@@ -192,13 +446,115 @@ impl Generator {
                 &self.root,
             )
             .context("Rendering index.html")?;
+        self.record_page_output(&self.root.join("index.html"))?;
 
         self.theme.render_css(&self.root).context("Rendering css")?;
+        self.record_page_output(&self.root.join("style.css"))?;
+
+        self.gen_feeds().context("Generating feeds")?;
+
+        self.gen_sitemap().context("Generating sitemap")?;
+
+        Ok(())
+    }
 
-        self.render_cache
-            .save(&self.root)
-            .context("Saving render cache")?;
+    fn gen_feeds(&self) -> Result<()> {
+        let base_url = match self.base_url()? {
+            Some(base_url) => base_url,
+            None => {
+                println!("No base_url in config, skipping feeds");
+                return Ok(());
+            }
+        };
+
+        let mut entries = Vec::new();
+        for (path, doc) in self.manifest.posts.docs() {
+            if !self.has_content(doc.id) {
+                continue;
+            }
+            entries.push(FeedEntry {
+                title: doc.name.clone(),
+                link: self.absolute_url(&base_url, &self.page_link(&path))?,
+                image: self.absolute_url(&base_url, &self.preview_image(doc.id))?,
+                updated: doc.modified_client,
+            });
+        }
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.updated));
+
+        Feed {
+            title: self.title(),
+            site_url: base_url.as_str(),
+            entries: &entries,
+        }
+        .write_all(&self.root)?;
+        for feed in ["feed.xml", "rss.xml", "feed.json"] {
+            self.record_page_output(&self.root.join(feed))?;
+        }
+        Ok(())
+    }
+
+    fn gen_sitemap(&self) -> Result<()> {
+        let base_url = match self.base_url()? {
+            Some(base_url) => base_url,
+            None => {
+                println!("No base_url in config, skipping sitemap");
+                sitemap::write_robots(&self.root.join("robots.txt"), None)?;
+                self.record_page_output(&self.root.join("robots.txt"))?;
+                return Ok(());
+            }
+        };
+
+        let mut entries = vec![SitemapEntry {
+            loc: self.absolute_url(&base_url, &self.prefix.join("index.html"))?,
+            lastmod: self.manifest.docs().iter().map(|d| d.modified_client).max(),
+        }];
+        self.sitemap_folder(&base_url, &[], &self.manifest.posts, &mut entries)?;
+
+        sitemap::write_sitemap(&self.root.join("sitemap.xml"), &entries)?;
+        self.record_page_output(&self.root.join("sitemap.xml"))?;
+
+        let sitemap_url = self.absolute_url(&base_url, &self.prefix.join("sitemap.xml"))?;
+        sitemap::write_robots(&self.root.join("robots.txt"), Some(&sitemap_url))?;
+        self.record_page_output(&self.root.join("robots.txt"))?;
+        Ok(())
+    }
+
+    fn sitemap_folder(
+        &self,
+        base_url: &url::Url,
+        folders: &[String],
+        posts: &Posts,
+        entries: &mut Vec<SitemapEntry>,
+    ) -> Result<()> {
+        if self.is_noindex(folders) {
+            return Ok(());
+        }
+
+        // The top level `Posts` folder is listed on the index page
+        if !folders.is_empty() {
+            entries.push(SitemapEntry {
+                loc: self.absolute_url(base_url, &self.page_link(folders))?,
+                lastmod: posts.docs().values().map(|d| d.modified_client).max(),
+            });
+        }
+
+        for doc in posts.documents.values() {
+            if !self.has_content(doc.id) {
+                continue;
+            }
+            let mut path = folders.to_vec();
+            path.push(doc.name.clone());
+            entries.push(SitemapEntry {
+                loc: self.absolute_url(base_url, &self.page_link(&path))?,
+                lastmod: Some(doc.modified_client),
+            });
+        }
 
+        for (sub_folder_name, sub_folder_posts) in posts.folders.iter() {
+            let mut sub_folder = folders.to_vec();
+            sub_folder.push(sub_folder_name.clone());
+            self.sitemap_folder(base_url, &sub_folder, sub_folder_posts, entries)?;
+        }
         Ok(())
     }
 
@@ -213,6 +569,26 @@ impl Generator {
         // TODO: replace this with a breadcrumbs_to_path method on the Site
         let doc_path = parent.join(format!("{}.html", sanitized_name));
 
+        let pdf_pages = if self.config.pdf && self.has_content(id) {
+            self.doc_pdf_pages(id)?
+        } else {
+            None
+        };
+        let pdf_link = match pdf_pages {
+            Some(pages) => {
+                let pdf_path = parent.join(format!("{}.pdf", sanitized_name));
+                let bookmarks = [Bookmark {
+                    title: name.to_string(),
+                    page: 0,
+                    children: Vec::new(),
+                }];
+                pdf::write_pdf(&pdf_path, &pages, &bookmarks).context("Writing document pdf")?;
+                self.record_page_output(&pdf_path)?;
+                Some(self.relative_to_root(&pdf_path)?)
+            }
+            None => None,
+        };
+
         self.theme
             .render_document(
                 &json!({
@@ -227,11 +603,15 @@ impl Generator {
                     "logo": self.logo_svg(),
                     "back_link": breadcrumbs.iter().last().map(|(_, link)| link).unwrap(),
                     "pages": self.doc_pages(id),
+                    "images": self.doc_images(id)?,
                     "render_nav_thumbnails": self.doc_pages(id).len() > 1,
+                    "noindex": self.is_noindex(&breadcrumb_folders(breadcrumbs)),
+                    "pdf": pdf_link,
                 }),
                 &doc_path,
             )
             .context("Rendering document html")?;
+        self.record_page_output(&doc_path)?;
 
         self.relative_to_root(&doc_path)
     }
@@ -256,6 +636,7 @@ impl Generator {
 
         let mut breadcrumbs_for_children = breadcrumbs.to_vec();
         breadcrumbs_for_children.push((folder.to_string(), folder_link.clone()));
+        let noindex = self.is_noindex(&breadcrumb_folders(&breadcrumbs_for_children));
         for doc in posts.documents.values() {
             let doc_path = self
                 .gen_doc(&breadcrumbs_for_children, &folder_path, &doc.name, doc.id)
@@ -275,6 +656,20 @@ impl Generator {
             sub_folders.push((sub_folder_name.to_string(), sub_folder_path));
         }
 
+        let pdf_link = if self.config.pdf {
+            let (pages, bookmark) = self.pdf_book(folder, posts)?;
+            if pages.is_empty() {
+                None
+            } else {
+                let pdf_path = parent.join(format!("{}.pdf", sanitized_folder));
+                pdf::write_pdf(&pdf_path, &pages, &[bookmark]).context("Writing folder pdf")?;
+                self.record_page_output(&pdf_path)?;
+                Some(self.relative_to_root(&pdf_path)?)
+            }
+        } else {
+            None
+        };
+
         self.theme
             .render_folder(
                 &json!({
@@ -288,25 +683,31 @@ impl Generator {
                     .map(|(name, link)| json!({"name": name, "link": link}))
                     .collect::<Vec<_>>(),
                 "back_link": breadcrumbs.iter().last().map(|(_, link)| link).unwrap(),
-                "documents": docs.into_iter().filter(|(_, id, _)| self.has_content(*id)).map(|(name, id, link)| json!({
+                "documents": docs.into_iter().filter(|(_, id, _)| self.has_content(*id)).map(|(name, id, link)| Ok(json!({
                     "name": name,
                     "svg": self.doc_first_page(id),
+                    "thumbnail": self.page_image(id, self.doc_first_page(id))?,
                     "link": link,
-                })).collect::<Vec<_>>(),
+                }))).collect::<Result<Vec<_>>>()?,
                 "folders": sub_folders.into_iter().map(|(name, link)| json!({
                     "name": name,
                     "link": link,
                 })).collect::<Vec<_>>(),
+                "noindex": noindex,
+                "pdf": pdf_link,
                 }),
                 &folder_html_path,
             )
             .context("Rendering folder html")?;
+        self.record_page_output(&folder_html_path)?;
 
         Ok(folder_link)
     }
 
-    fn render_all_svgs(&mut self, material_root: &Path) -> Result<BTreeMap<Uuid, Vec<PathBuf>>> {
-        let zip_dir = material_root.join("zip");
+    fn render_all_svgs(&self) -> Result<BTreeMap<Uuid, Vec<PathBuf>>> {
+        lock(&self.rendered_outputs)?.clear();
+        lock(&self.entries)?.clear();
+        let zip_dir = self.material_path.join("zip");
 
         let mut doc_svgs: BTreeMap<Uuid, Vec<PathBuf>> = Default::default();
 
@@ -317,160 +718,324 @@ impl Generator {
                 .context("Rendering logo svg")?,
         ]);
 
-        doc_svgs.extend(
-            self.manifest
-                .posts
-                .docs()
-                .par_iter()
-                .map(|(_, doc)| {
-                    self.render_doc_meta(doc, &zip_dir, false)
-                        .context("Rendering document svg")
-                })
-                .collect::<Result<Vec<_>>>()
-                .context("Rendering documents")?,
-        );
-
-        self.render_cache.cache.extend(
-            self.manifest
-                .docs()
-                .iter()
-                .map(|doc| (doc.id, doc.modified_client)),
-        );
+        // A post that fails to render is left without pages rather than
+        // failing the whole build.
+        let rendered = self
+            .manifest
+            .posts
+            .docs()
+            .par_iter()
+            .map(|(_, doc)| (doc.id, self.render_doc_meta(doc, &zip_dir, false)))
+            .collect::<Vec<_>>();
+        for (id, result) in rendered {
+            match result {
+                Ok(pages) => {
+                    doc_svgs.insert(pages.0, pages.1);
+                }
+                Err(e) => {
+                    println!("Skipping document: {:#}", e);
+                    doc_svgs.insert(id, Vec::new());
+                }
+            }
+        }
 
         Ok(doc_svgs)
     }
 
     fn render_doc_meta(
         &self,
-        doc: &crate::manifest::DocumentMeta,
+        doc: &DocumentMeta,
         zip_dir: &Path,
         crop: bool,
     ) -> Result<(Uuid, Vec<PathBuf>)> {
-        if let Some(last_modified) = self.render_cache.get(&doc.id) {
-            if last_modified == &doc.modified_client {
-                let notebook_root = self.root.join("svg").join(format!("{}", doc.id));
-                let mut pages = Vec::new();
-                for entry in std::fs::read_dir(notebook_root)? {
-                    let entry = entry?;
-                    let path = entry.path();
-                    let path = self.prefix.join(
-                        path.strip_prefix(&self.root)
-                            .context("Stripping site root form svg paths")?
-                            .to_path_buf(),
-                    );
-                    pages.push(path)
-                }
-                pages.sort_by_key(|page| {
-                    page.file_stem()
-                        .unwrap()
-                        .to_os_string()
-                        .into_string()
-                        .unwrap()
-                        .parse::<u16>()
-                        .unwrap()
-                });
-                return Ok((doc.id, pages));
-            }
-        }
+        let zip_path = zip_dir.join(format!("{}.zip", doc.id));
+        let entry = render_entry(&self.render_cache, doc, &zip_path, crop)?;
+        self.rasterize_entry(&entry).context("Rasterizing svg's")?;
 
         let pages = self
-            .render_notebook_zip(doc.id, &zip_dir.join(format!("{}.zip", doc.id)), crop)
-            .context("Rendering notebook zip")?;
-
+            .link_entry(doc.id, &entry)
+            .context("Linking rendered pages into the build")?;
         Ok((doc.id, pages))
     }
 
-    fn render_notebook_zip(
-        &self,
-        id: Uuid,
-        zip_path: &Path,
-        auto_crop: bool,
-    ) -> Result<Vec<PathBuf>> {
-        let notebook_root = self.root.join("svg").join(format!("{}", id));
-        let _ = std::fs::remove_dir_all(&notebook_root);
-        std::fs::create_dir_all(&notebook_root).context("Creating notebook svg directory")?;
-
-        let zip_file = std::fs::File::open(zip_path).context("Opening zip file")?;
-        let mut zip = zip::ZipArchive::new(zip_file).context("Reading ZipArchive")?;
-        let mut rendered_svgs = Vec::new();
-
-        println!("Reading page templates..");
-
-        let templates: Option<Vec<String>> = {
-            let mut templates: Option<Vec<String>> = None;
-            for i in 0..zip.len() {
-                let mut file = zip
-                    .by_index(i)
-                    .context("Attempting to index into the zip files")?;
-                if file.name().ends_with(".pagedata") {
-                    let mut buf = String::new();
-                    file.read_to_string(&mut buf)?;
-                    templates = Some(Vec::from_iter(buf.lines().map(str::to_string)));
-                    break;
+    /// Hard-links (or copies) a render cache entry into the build, replacing
+    /// whatever was there for the document before.
+    fn link_entry(&self, id: Uuid, entry: &Path) -> Result<Vec<PathBuf>> {
+        let svg_dir = self.root.join("svg").join(format!("{}", id));
+        let raster_dir = self.root.join("raster").join(format!("{}", id));
+        let _ = std::fs::remove_dir_all(&svg_dir);
+        let _ = std::fs::remove_dir_all(&raster_dir);
+        std::fs::create_dir_all(&svg_dir).context("Creating notebook svg directory")?;
+
+        let mut outputs = Vec::new();
+        let mut pages = Vec::new();
+        for svg in render_cache::entry_svgs(entry)? {
+            let out = svg_dir.join(svg.file_name().unwrap());
+            render_cache::link_or_copy(&svg, &out)?;
+            pages.push(self.relative_to_root(&out)?);
+            outputs.push(out);
+        }
+
+        let entry_raster = entry.join("raster");
+        if entry_raster.is_dir() {
+            std::fs::create_dir_all(&raster_dir).context("Creating raster directory")?;
+            for raster in std::fs::read_dir(&entry_raster).context("Listing rasterized pages")? {
+                let raster = raster?.path();
+                if raster.extension().and_then(std::ffi::OsStr::to_str) == Some("tmp") {
+                    continue;
                 }
+                let out = raster_dir.join(raster.file_name().unwrap());
+                render_cache::link_or_copy(&raster, &out)?;
+                outputs.push(out);
             }
-            templates
-        };
+        }
+
+        lock(&self.rendered_outputs)?.extend(outputs);
+        lock(&self.entries)?.insert(id, entry.to_path_buf());
+        Ok(pages)
+    }
+}
 
-        println!("Templates {:?}", templates);
+/// The render cache entry of a document, rendering it if it isn't cached.
+pub(crate) fn render_entry(
+    cache: &RenderCache,
+    doc: &DocumentMeta,
+    zip_path: &Path,
+    crop: bool,
+) -> Result<PathBuf> {
+    let zip_bytes = std::fs::read(zip_path).context("Reading zip file")?;
+    let key = RenderCache::key(&zip_bytes, &RenderSettings::new(RENDER_SCALE, crop))?;
+
+    match cache.get(&key) {
+        Some(entry) => Ok(entry),
+        None => cache
+            .insert_with(&key, |out_dir| {
+                render_notebook_zip(doc.id, &doc.name, zip_path, crop, out_dir)
+            })
+            .with_context(|| format!("Rendering notebook zip of {:?}", doc.name)),
+    }
+}
 
+fn render_notebook_zip(
+    id: Uuid,
+    name: &str,
+    zip_path: &Path,
+    auto_crop: bool,
+    out_dir: &Path,
+) -> Result<()> {
+    let notebook_root = out_dir.join("svg");
+    std::fs::create_dir_all(&notebook_root).context("Creating notebook svg directory")?;
+
+    let zip_file = std::fs::File::open(zip_path).context("Opening zip file")?;
+    let mut zip = zip::ZipArchive::new(zip_file).context("Reading ZipArchive")?;
+    let mut pdf_pages = Vec::new();
+
+    println!("Reading page templates..");
+
+    let templates: Option<Vec<String>> = {
+        let mut templates: Option<Vec<String>> = None;
         for i in 0..zip.len() {
             let mut file = zip
                 .by_index(i)
                 .context("Attempting to index into the zip files")?;
-            if file.name().ends_with(".rm") {
-                let lines =
-                    lines_are_rusty::LinesData::parse(&mut file).context("Parsing .rm file")?;
-                // file name has pattern <uuid>/<page-num>.rm, we just want the page-num.
-                let page_number: usize = file
-                    .name()
-                    .trim_start_matches(&format!("{}/", id))
-                    .trim_end_matches(".rm")
-                    .parse()?;
-                println!("Rendering {} p{} svg", id, page_number);
-
-                let output_path = notebook_root.join(format!("{}.svg", page_number));
-                let mut output =
-                    std::fs::File::create(&output_path).context("Creating output file for svg")?;
-                let template = templates
-                    .as_ref()
-                    .and_then(|ts| ts.get(page_number))
-                    .and_then(|ts| {
-                        if lines_are_rusty::render::templates::TEMPLATES.contains_key(&ts) {
-                            Some(ts)
-                        } else {
-                            None
-                        }
-                    })
-                    .map(String::as_str);
-                let debug = false;
-
-                println!("Template: {:?}", template);
-
-                lines_are_rusty::render_svg(
-                    &mut output,
-                    &lines.pages[0],
-                    auto_crop,
-                    &Default::default(),
-                    2.0,
-                    template,
-                    debug,
-                )
-                .context("Rendering document page svg")?;
-
-                rendered_svgs.push(
-                    self.prefix.join(
-                        output_path
-                            .strip_prefix(&self.root)
-                            .context("Stripping site root form svg paths")?
-                            .to_path_buf(),
-                    ),
-                );
+            if file.name().ends_with(".pagedata") {
+                let mut buf = String::new();
+                file.read_to_string(&mut buf)?;
+                templates = Some(Vec::from_iter(buf.lines().map(str::to_string)));
+                break;
             }
         }
+        templates
+    };
+
+    println!("Templates {:?}", templates);
+
+    // Pages are rendered in the order the .content lists them, including
+    // pages without strokes. Annotated PDFs and EPUBs follow its mapping
+    // to the pages of the underlying PDF.
+    let content = Content::from_zip(&mut zip, id)
+        .context("Reading .content")?
+        .filter(|content| !content.pages.is_empty());
+    let background_dir = out_dir.join("background");
+    let (background, plan) = match content {
+        Some(mut content) => {
+            if let Some(templates) = &templates {
+                content.apply_pagedata(templates);
+            }
+            let background = if content.has_background() {
+                PdfBackground::extract(&mut zip, id, &background_dir)
+                    .context("Extracting document pdf")?
+            } else {
+                None
+            };
+            let plan = content
+                .pages
+                .into_iter()
+                .enumerate()
+                .map(|(page_number, page)| {
+                    let rm_entry = [
+                        format!("{}/{}.rm", id, page.id),
+                        format!("{}/{}.rm", id, page_number),
+                    ]
+                    .into_iter()
+                    .find(|name| zip.file_names().any(|n| n == name));
+                    (page_number, rm_entry, page.redirect, page.template)
+                })
+                .collect::<Vec<_>>();
+            (background, plan)
+        }
+        None => {
+            // Without a page list fall back to the numbered .rm files
+            let mut plan = zip
+                .file_names()
+                .filter(|name| name.ends_with(".rm"))
+                .map(|name| {
+                    // file name has pattern <uuid>/<page-num>.rm, we just want the page-num.
+                    let page_number: usize = name
+                        .trim_start_matches(&format!("{}/", id))
+                        .trim_end_matches(".rm")
+                        .parse()?;
+                    let template = templates
+                        .as_ref()
+                        .and_then(|ts| ts.get(page_number))
+                        .cloned();
+                    Ok((page_number, Some(name.to_string()), None, template))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            plan.sort_by_key(|(page_number, ..)| *page_number);
+            (None, plan)
+        }
+    };
+
+    for (page_number, rm_entry, redirect, template) in plan {
+        println!("Rendering {} p{} svg", id, page_number);
 
-        Ok(rendered_svgs)
+        let template = template
+            .as_ref()
+            .filter(|ts| lines_are_rusty::render::templates::TEMPLATES.contains_key(ts))
+            .map(String::as_str);
+        let debug = false;
+
+        println!("Template: {:?}", template);
+
+        let mut svg = Vec::new();
+        match rm_entry {
+            Some(rm_entry) => {
+                let mut data = Vec::new();
+                zip.by_name(&rm_entry)
+                    .context("Opening .rm file in zip")?
+                    .read_to_end(&mut data)
+                    .context("Reading .rm file")?;
+
+                match rm6::version(&data) {
+                    Some(3) | Some(5) => {
+                        let lines =
+                            lines_are_rusty::LinesData::parse(&mut std::io::Cursor::new(data))
+                                .with_context(|| format!("Parsing {} of {:?}", rm_entry, name))?;
+
+                        lines_are_rusty::render_svg(
+                            &mut svg,
+                            &lines.pages[0],
+                            auto_crop,
+                            &Default::default(),
+                            RENDER_SCALE,
+                            template,
+                            debug,
+                        )
+                        .context("Rendering document page svg")?;
+
+                        pdf_pages
+                            .push((page_number, PdfPage::from_lines(&lines.pages[0], template)?));
+                    }
+                    Some(6) => {
+                        let page = rm6::Page::parse(&data)
+                            .with_context(|| format!("Parsing {} of {:?}", rm_entry, name))?;
+                        page.render_svg(&mut svg, template, auto_crop)
+                            .context("Rendering document page svg")?;
+
+                        pdf_pages.push((page_number, PdfPage::from_rm6(&page, template)?));
+                    }
+                    Some(version) => {
+                        return Err(anyhow!(
+                            "Document {:?} has pages in .rm version {}, which isn't supported",
+                            name,
+                            version
+                        ));
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "Document {:?} has a page that isn't a .rm file: {}",
+                            name,
+                            rm_entry
+                        ));
+                    }
+                }
+            }
+            None => {
+                svg.extend_from_slice(background::blank_page().as_bytes());
+                pdf_pages.push((
+                    page_number,
+                    PdfPage {
+                        content: String::new(),
+                        background: false,
+                    },
+                ));
+            }
+        }
+
+        if let (Some(background), Some(redirect)) = (&background, redirect) {
+            let page_background = background
+                .page_svg(redirect)
+                .with_context(|| format!("Rendering pdf page {} of {}", redirect, id))?;
+            let strokes = String::from_utf8(svg).context("Rendered svg is not utf8")?;
+            svg = background::compose(&strokes, &page_background)?.into_bytes();
+            if let Some((_, page)) = pdf_pages.last_mut() {
+                page.background = true;
+            }
+        }
+
+        let output_path = notebook_root.join(format!("{}.svg", page_number));
+        std::fs::write(&output_path, svg).context("Writing page svg")?;
     }
+    let _ = std::fs::remove_dir_all(&background_dir);
+
+    // Kept whether or not PDFs are enabled so toggling them doesn't
+    // invalidate the render cache.
+    pdf_pages.sort_by_key(|(page_number, _)| *page_number);
+    let pdf_pages: Vec<PdfPage> = pdf_pages.into_iter().map(|(_, page)| page).collect();
+    let pdf_pages_file =
+        std::fs::File::create(out_dir.join("pdf.json")).context("Creating pdf pages file")?;
+    serde_json::to_writer(pdf_pages_file, &pdf_pages).context("Writing pdf pages")?;
+
+    Ok(())
+}
+
+fn offset_bookmark(bookmark: &mut Bookmark, offset: usize) {
+    bookmark.page += offset;
+    for child in bookmark.children.iter_mut() {
+        offset_bookmark(child, offset);
+    }
+}
+
+/// The `Posts` folders leading to a page, the first breadcrumb is always Home.
+fn breadcrumb_folders(breadcrumbs: &[(String, PathBuf)]) -> Vec<String> {
+    breadcrumbs
+        .iter()
+        .skip(1)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Locks state shared between the render threads, which is poisoned if one
+/// of them panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| anyhow!("Generator state poisoned"))
+}
+
+fn new_build_nonce() -> String {
+    chrono::Utc::now().format("%Y-%m-%dT%H-%M-%S").to_string()
 }
 
 pub fn sanitize(name: &str) -> String {
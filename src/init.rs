@@ -0,0 +1,321 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use async_recursion::async_recursion;
+use remarkable_cloud_api::Uuid;
+
+use crate::cloud::CloudEndpoints;
+use crate::config::Config;
+use crate::export::RENDERS_DIR;
+use crate::manifest::{DocumentTree, Node};
+use crate::source::CloudBackend;
+
+/// Creates the site folder on the backend from the starter tree in `starter`
+/// and writes a config pointing at it.
+///
+/// If a top level folder named `folder_name` already exists, e.g. from an
+/// init that was interrupted, only what it is missing gets uploaded. With
+/// `dry_run` the planned tree is printed and nothing is changed.
+pub async fn init(
+    backend: &impl CloudBackend,
+    folder_name: String,
+    starter: PathBuf,
+    cloud: CloudEndpoints,
+    config_path: PathBuf,
+    dry_run: bool,
+) -> Result<()> {
+    let docs = backend
+        .list_documents()
+        .await
+        .context("Fetching all document metadata")?;
+
+    let existing_folders: Vec<Node> = docs
+        .child_nodes(None)
+        .into_iter()
+        .filter(|d| d.visible_name == folder_name && d.doc_type == "CollectionType")
+        .collect();
+
+    let folder_id = match existing_folders.as_slice() {
+        [] => {
+            println!("+ {}/", folder_name);
+            if dry_run {
+                None
+            } else {
+                Some(
+                    backend
+                        .create_folder(Uuid::new_v4(), folder_name.clone(), None)
+                        .await
+                        .context("Creating site folder on remarkable")?,
+                )
+            }
+        }
+        [existing] => {
+            println!("  {}/ (exists, uploading what's missing)", folder_name);
+            Some(existing.id)
+        }
+        _ => {
+            return Err(anyhow!(
+                "Choose a unique folder name:  {} folder(s) with the name '{}'",
+                existing_folders.len(),
+                folder_name
+            ))
+        }
+    };
+
+    let options = SyncOptions {
+        dry_run,
+        replace: false,
+        site_root: true,
+    };
+    sync_directory(backend, &docs, &starter, folder_id, options, 1).await?;
+
+    if dry_run {
+        println!("Dry run, nothing was uploaded");
+        return Ok(());
+    }
+    let folder_id = folder_id.ok_or_else(|| anyhow!("Site folder was not created"))?;
+
+    let config = Config {
+        site_root: folder_id.to_string(),
+        title: folder_name,
+        theme: "marker".to_string(),
+        base_url: None,
+        noindex: Vec::new(),
+        pdf: false,
+        raster_widths: Vec::new(),
+        raster_formats: Vec::new(),
+        cache_dir: None,
+        cloud,
+    };
+
+    if let Some(config_parent) = config_path.parent() {
+        std::fs::create_dir_all(config_parent).context("Ensuring config path parent exists")?;
+    }
+    println!("Saving config file");
+    config.save(&config_path).context("Saving config")?;
+
+    Ok(())
+}
+
+/// How `sync_directory` treats what it finds.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SyncOptions {
+    /// Print what would be uploaded without changing anything
+    pub dry_run: bool,
+    /// Upload documents whose name is taken again and delete the old ones
+    pub replace: bool,
+    /// `dir` is the top of a starter tree, whose `renders/` directory holds
+    /// exported renders rather than documents
+    pub site_root: bool,
+}
+
+/// Uploads whatever in `dir` has no folder or document of the same name in
+/// `rm_folder_id`, recursing into folders. `None` is a folder that doesn't
+/// exist yet, which only happens on a dry run.
+///
+/// Documents are matched by name from the listing and a match is never
+/// overwritten, but a PDF or EPUB whose bytes differ from the existing one's
+/// is reported as differing. With `options.replace`, documents that
+/// already exist are uploaded again and every old one of that name deleted.
+#[async_recursion]
+pub(crate) async fn sync_directory<B, T>(
+    backend: &B,
+    docs: &T,
+    dir: &Path,
+    rm_folder_id: Option<Uuid>,
+    options: SyncOptions,
+    depth: usize,
+) -> Result<()>
+where
+    B: CloudBackend,
+    T: DocumentTree + Sync,
+{
+    let existing = match rm_folder_id {
+        Some(id) => docs.child_nodes(Some(id)),
+        None => Vec::new(),
+    };
+    let find = |name: &str, folder: bool| -> Vec<Uuid> {
+        existing
+            .iter()
+            .filter(|n| n.visible_name == name && (n.doc_type == "CollectionType") == folder)
+            .map(|n| n.id)
+            .collect()
+    };
+    let indent = "  ".repeat(depth);
+
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Listing {:?}", dir))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Listing {:?}", dir))?;
+    entries.sort();
+
+    for path in entries {
+        let file_name = path
+            .file_name()
+            .and_then(std::ffi::OsStr::to_str)
+            .ok_or_else(|| anyhow!("File name is not valid UTF-8: {:?}", path))?;
+
+        if path.is_dir() {
+            if options.site_root && file_name == RENDERS_DIR {
+                continue;
+            }
+            let sub_folder_id = match find(file_name, true).first().copied() {
+                Some(id) => {
+                    println!("{}  {}/", indent, file_name);
+                    Some(id)
+                }
+                None => {
+                    println!("{}+ {}/", indent, file_name);
+                    match rm_folder_id.filter(|_| !options.dry_run) {
+                        Some(parent) => Some(
+                            backend
+                                .create_folder(Uuid::new_v4(), file_name.to_string(), Some(parent))
+                                .await
+                                .context("Creating folder on remarkable")?,
+                        ),
+                        None => None,
+                    }
+                }
+            };
+            sync_directory(
+                backend,
+                docs,
+                &path,
+                sub_folder_id,
+                SyncOptions {
+                    site_root: false,
+                    ..options
+                },
+                depth + 1,
+            )
+            .await?;
+            continue;
+        }
+
+        let extension = match document_type(&path) {
+            Some(extension) => extension,
+            None => {
+                println!(
+                    "{}! {} (skipped, not a .zip, .pdf or .epub)",
+                    indent, file_name
+                );
+                continue;
+            }
+        };
+        let name = path
+            .file_stem()
+            .and_then(std::ffi::OsStr::to_str)
+            .map(String::from)
+            .ok_or_else(|| anyhow!("Document has no name: {:?}", path))?;
+
+        let replaced = find(&name, false);
+        match replaced.first().copied() {
+            Some(id) if !options.replace => {
+                if matches_existing(backend, id, &path, extension).await? {
+                    println!("{}  {}", indent, file_name);
+                } else {
+                    println!(
+                        "{}! {} (skipped, differs from the existing document of that name)",
+                        indent, file_name
+                    );
+                }
+                continue;
+            }
+            Some(_) => println!("{}~ {}", indent, file_name),
+            None => println!("{}+ {}", indent, file_name),
+        }
+        let parent = match rm_folder_id.filter(|_| !options.dry_run) {
+            Some(parent) => parent,
+            None => continue,
+        };
+
+        upload_file(backend, &path, extension, name, parent).await?;
+        for old_id in replaced {
+            backend
+                .delete(old_id)
+                .await
+                .with_context(|| format!("Deleting the document {:?} replaced", path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the existing document `id`, which the listing already shows is a
+/// document named like `path`, looks like an upload of it. Notebook zips
+/// match on that alone, only PDFs and EPUBs are downloaded to compare bytes.
+async fn matches_existing(
+    backend: &impl CloudBackend,
+    id: Uuid,
+    path: &Path,
+    file_type: &str,
+) -> Result<bool> {
+    if file_type == "zip" {
+        return Ok(true);
+    }
+    let local = std::fs::read(path).with_context(|| format!("Reading {:?}", path))?;
+    let zip = backend
+        .download_zip(id)
+        .await
+        .with_context(|| format!("Downloading the existing document for {:?}", path))?;
+    let mut zip =
+        zip::ZipArchive::new(std::io::Cursor::new(zip)).context("Reading existing document zip")?;
+    let mut file = match zip.by_name(&format!("{}.{}", id, file_type)) {
+        Ok(file) if file.size() == local.len() as u64 => file,
+        _ => return Ok(false),
+    };
+    let mut existing = Vec::new();
+    file.read_to_end(&mut existing)
+        .context("Reading existing document file")?;
+    Ok(existing == local)
+}
+
+/// The kind of document a local file can be uploaded as: `zip` for
+/// notebook zips, `pdf` or `epub`.
+pub(crate) fn document_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    ["zip", "pdf", "epub"]
+        .into_iter()
+        .find(|file_type| *file_type == extension)
+}
+
+/// Uploads a notebook zip, PDF or EPUB as a new document named `name`.
+pub(crate) async fn upload_file(
+    backend: &impl CloudBackend,
+    path: &Path,
+    file_type: &str,
+    name: String,
+    parent: Uuid,
+) -> Result<Uuid> {
+    let id = Uuid::new_v4();
+    let bytes = std::fs::read(path).with_context(|| format!("Reading {:?}", path))?;
+    let zip = match file_type {
+        "zip" => bytes,
+        file_type => {
+            document_zip(id, file_type, &bytes).with_context(|| format!("Packaging {:?}", path))?
+        }
+    };
+    backend
+        .upload_notebook(id, name, Some(parent), zip)
+        .await
+        .with_context(|| format!("Uploading {:?} to remarkable", path))
+}
+
+/// Wraps a PDF or EPUB in a document zip the way the tablet stores them:
+/// the file itself next to a `.content` naming its type.
+fn document_zip(id: Uuid, file_type: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default();
+
+    zip.start_file(format!("{}.{}", id, file_type), options)?;
+    zip.write_all(bytes)?;
+
+    zip.start_file(format!("{}.content", id), options)?;
+    let content = serde_json::json!({ "fileType": file_type });
+    serde_json::to_writer(&mut zip, &content)?;
+
+    zip.start_file(format!("{}.pagedata", id), options)?;
+
+    Ok(zip.finish()?.into_inner())
+}
@@ -1,9 +1,45 @@
+mod background;
+mod cloud;
 mod config;
+mod content;
+mod export;
+#[cfg(feature = "fake-cloud")]
+mod fake_cloud;
+mod feed;
+mod fetch;
 mod generator;
+mod init;
 mod manifest;
+mod pdf;
+mod prune;
+mod push;
+mod raster;
+mod render_cache;
+mod rm6;
+mod sitemap;
+mod source;
+mod starter;
+mod sync15;
 mod theme;
+mod usb;
+mod xochitl;
 
+pub use cloud::CloudEndpoints;
 pub use config::Config;
+pub use export::{export, ExportOptions};
+#[cfg(feature = "fake-cloud")]
+pub use fake_cloud::FakeCloud;
+pub use fetch::{fetch, FetchOptions};
 pub use generator::{sanitize, Generator};
-pub use manifest::Manifest;
+pub use init::init;
+pub use manifest::{DocumentTree, Manifest, Node, NodeTree};
+pub use pdf::{write_pdf, Bookmark, PdfPage};
+pub use push::push;
+pub use raster::{RasterFormat, Rasterizer};
+pub use render_cache::{RenderCache, RenderSettings};
+pub use source::{CloudBackend, MaterialSource, RmCloud};
+pub use starter::Starter;
+pub use sync15::SyncClient;
 pub use theme::Theme;
+pub use usb::UsbClient;
+pub use xochitl::Xochitl;
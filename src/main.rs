@@ -1,20 +1,51 @@
-use std::collections::BTreeMap;
-use std::io::Write;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
-use async_recursion::async_recursion;
-use remarkable_cloud_api::{reqwest, Client, ClientState, Parent, Uuid};
+use remarkable_cloud_api::reqwest;
 use structopt::StructOpt;
+use tokio::sync::broadcast;
 
+mod background;
+mod cloud;
 mod config;
+mod content;
+mod credentials;
+mod export;
+mod feed;
+mod fetch;
 mod generator;
+mod init;
 mod manifest;
+mod pdf;
+mod prune;
+mod push;
+mod raster;
+mod render_cache;
+mod rm6;
+mod serve;
+mod sitemap;
+mod source;
+mod starter;
+mod sync15;
 mod theme;
+mod usb;
+mod watch;
+mod xochitl;
 
+use cloud::CloudEndpoints;
 use config::Config;
+use credentials::Credentials;
+use export::ExportOptions;
+use fetch::FetchOptions;
 use generator::Generator;
 use manifest::Manifest;
+use render_cache::RenderCache;
+use source::RmCloud;
+use starter::Starter;
+use sync15::SyncClient;
+use usb::UsbClient;
+use xochitl::Xochitl;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
@@ -29,12 +60,47 @@ struct Opt {
 #[derive(Debug, StructOpt)]
 #[structopt(about = "First fetch the raw site material from rM cloud, then generate the site")]
 enum Action {
+    /// Pair with rM cloud using a one-time code from
+    /// https://my.remarkable.com/device/desktop/connect and store the device token
+    Register {
+        code: String,
+        #[structopt(flatten)]
+        cloud: CloudEndpoints,
+    },
+    /// Upload the starter site into a new rM folder and write a config for it, resuming
+    /// into the folder if it already exists
     Init {
-        device_token: String,
         folder: String,
+        #[structopt(flatten)]
+        cloud: CloudEndpoints,
+        /// Name of a built-in starter (see `starter list`) or a path to a directory laid out like one
+        #[structopt(long, default_value = Starter::DEFAULT)]
+        starter: String,
+        /// Print the folders and documents that would be uploaded without uploading them
+        #[structopt(long)]
+        dry_run: bool,
     },
     Fetch {
-        device_token: String,
+        #[structopt(parse(from_os_str))]
+        material_path: PathBuf,
+        /// Fetch over the tablet's USB web interface instead of rM cloud,
+        /// optionally from a different host: `--usb=<host>`
+        #[structopt(long, require_equals = true)]
+        usb: Option<Option<String>>,
+        /// Use the hash-tree based sync protocol that current rM cloud accounts use
+        #[structopt(long)]
+        sync15: bool,
+        /// How many documents to download at the same time
+        #[structopt(long, default_value = "8")]
+        jobs: usize,
+        /// How many times to retry a download that failed with a transient error
+        #[structopt(long, default_value = "4")]
+        retries: u32,
+    },
+    /// Build the raw site material from a copy of the tablet's xochitl directory
+    Import {
+        #[structopt(parse(from_os_str))]
+        xochitl_path: PathBuf,
         #[structopt(parse(from_os_str))]
         material_path: PathBuf,
     },
@@ -43,167 +109,204 @@ enum Action {
         material_path: PathBuf,
         #[structopt(parse(from_os_str))]
         build_path: PathBuf,
+        /// Path prefix used for every link in the generated site
+        #[structopt(long, parse(from_os_str), default_value = "/")]
+        prefix: PathBuf,
+        /// Prefix links with the absolute build path so the site can be opened over file://
+        #[structopt(long)]
+        file_urls: bool,
+        /// Keep running and regenerate the site when material or theme files change
+        #[structopt(long)]
+        watch: bool,
+        /// Build the material from a local tree of notebook zips laid out like a starter
+        /// instead of from previously fetched rM cloud material
+        #[structopt(long, parse(from_os_str))]
+        from_dir: Option<PathBuf>,
+        /// Still build the site, but only list the orphaned files from earlier
+        /// builds instead of removing them
+        #[structopt(long, alias = "keep-orphans")]
+        dry_run: bool,
+    },
+    /// Generate the site and serve it locally, reloading open tabs on every rebuild
+    Serve {
+        #[structopt(parse(from_os_str))]
+        material_path: PathBuf,
+        /// Where to write the generated site, defaults to a temporary directory
+        #[structopt(long, parse(from_os_str))]
+        build_path: Option<PathBuf>,
+        #[structopt(long, default_value = "127.0.0.1:8000")]
+        addr: SocketAddr,
+    },
+    /// Upload a notebook zip, PDF or EPUB, or a directory of them, into the site folder
+    Push {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Folder to upload into, as a `/` separated path from the site root
+        #[structopt(long, default_value = "Posts")]
+        into: String,
+        /// Replace documents that already exist with the same name
+        #[structopt(long)]
+        replace: bool,
+        /// Allow pushing the site's Home or Logo document to the top of the site folder
+        #[structopt(long)]
+        site_page: bool,
+    },
+    /// Write the site material as a starter tree named after the documents, e.g. as a backup
+    Export {
+        #[structopt(parse(from_os_str))]
+        material_path: PathBuf,
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+        /// Also write a PDF of every document, under `renders/`
+        #[structopt(long)]
+        pdf: bool,
+        /// Also write the svg pages of every document, under `renders/`
+        #[structopt(long)]
+        svg: bool,
+    },
+    /// Manage the render cache shared between builds
+    Cache {
+        #[structopt(subcommand)]
+        action: CacheAction,
+    },
+    /// Inspect the built-in starter sites or package a new one
+    Starter {
+        #[structopt(subcommand)]
+        action: StarterAction,
     },
 }
 
-async fn init(client: Client, folder_name: String, config_path: PathBuf) -> Result<()> {
-    if let Some(config_parent) = config_path.parent() {
-        std::fs::create_dir_all(&config_parent).context("Ensuring config path parent exists")?;
-    }
-    let docs = client
-        .all_documents(false)
-        .await
-        .context("Fetching all document metadata from rM Cloud")?;
-
-    let folders_with_given_name = docs
-        .children(Parent::Root)
-        .into_iter()
-        .filter(|d| d.visible_name == folder_name && d.doc_type == "CollectionType")
-        .count();
-
-    if folders_with_given_name > 0 {
-        return Err(anyhow!(
-            "Choose a unique folder name:  {} folder(s) with the name '{}'",
-            folders_with_given_name,
-            folder_name
-        ));
-    }
-    println!("Creating folder on remarkable {:?}", folder_name);
-    let folder_id = client
-        .create_folder(Uuid::new_v4(), folder_name.clone(), Parent::Root)
-        .await
-        .context("Creating site folder on remarkable")?;
-
-    upload_directory(&client, &Path::new("starter"), folder_id).await?;
+#[derive(Debug, StructOpt)]
+enum StarterAction {
+    /// List the built-in starters and their files
+    List,
+    /// Write a built-in starter to a directory
+    Export {
+        name: String,
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+    },
+    /// Download a site folder from rM cloud as a starter directory, `out_dir` must be empty
+    Package {
+        /// `/` separated path of the site folder, e.g. `MRKR/Starter`
+        folder: String,
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+        #[structopt(flatten)]
+        cloud: CloudEndpoints,
+    },
+}
 
-    let config = Config {
-        site_root: folder_id.to_string(),
-        title: folder_name,
-        theme: "marker".to_string(),
-    };
+#[derive(Debug, StructOpt)]
+enum CacheAction {
+    /// Check every cached render, removing broken entries
+    Verify,
+    /// Remove every cached render
+    Clear,
+}
 
-    println!("Saving config file");
-    config.save(&config_path).context("Saving config")?;
+fn gen(
+    config_path: &Path,
+    material_path: &Path,
+    build_path: &Path,
+    prefix: PathBuf,
+    no_cache: bool,
+    dry_run: bool,
+) -> Result<Generator> {
+    let config = Config::load(config_path).context("Loading site config")?;
+    let generator = Generator::prepare(
+        config,
+        material_path.to_path_buf(),
+        build_path.to_path_buf(),
+        prefix,
+        no_cache,
+    )
+    .context("Preparing to generate site")?;
 
-    Ok(())
-}
+    generator.gen_index().context("Generating site")?;
 
-#[async_recursion]
-async fn upload_directory(client: &Client, dir: &Path, rm_folder_id: Uuid) -> Result<()> {
-    println!("Uploading {:?}", dir);
-    for entry in std::fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            let folder_name = path
-                .file_name()
-                .and_then(std::ffi::OsStr::to_str)
-                .map(String::from)
-                .ok_or_else(|| anyhow!("Couldn't get sub folder file name: {:?}", path))?;
-            let sub_folder_id = client
-                .create_folder(Uuid::new_v4(), folder_name, Parent::Node(rm_folder_id))
-                .await
-                .context("Creating folder on remarkable")?;
-
-            upload_directory(&client, &path, sub_folder_id).await?;
-        } else {
-            assert_eq!(
-                path.extension().and_then(std::ffi::OsStr::to_str),
-                Some("zip")
-            );
-
-            let notebook_name = path
-                .file_stem()
-                .and_then(std::ffi::OsStr::to_str)
-                .map(String::from)
-                .ok_or_else(|| anyhow!("Zip file has no name: {:?}", path))?;
-
-            let zip_file = std::fs::File::open(path).context("Opening notebook zip file")?;
-            let mut zip = zip::ZipArchive::new(zip_file).context("Reading ZipArchive")?;
-            client
-                .upload_notebook(
-                    Uuid::new_v4(),
-                    notebook_name,
-                    Parent::Node(rm_folder_id),
-                    &mut zip,
-                )
-                .await
-                .context("Creating notebook on remarkable")?;
+    let orphaned = generator
+        .prune(dry_run)
+        .context("Pruning orphaned output")?;
+    if dry_run {
+        println!("Would remove {} orphaned files:", orphaned.len());
+        for path in orphaned {
+            println!("  {}", path.display());
         }
     }
-    Ok(())
+    Ok(generator)
 }
 
-async fn fetch(config: Config, client: Client, output_path: &Path) -> Result<()> {
-    std::fs::create_dir_all(&output_path).context("Creating material output directory")?;
+async fn preview(
+    config_path: PathBuf,
+    material_path: PathBuf,
+    build_path: PathBuf,
+    addr: SocketAddr,
+    no_cache: bool,
+) -> Result<()> {
+    let generator = gen(
+        &config_path,
+        &material_path,
+        &build_path,
+        PathBuf::from("/"),
+        no_cache,
+        false,
+    )?;
 
-    let archives_dir = output_path.join("zip");
-    std::fs::create_dir_all(&archives_dir).context("Creating zip archives directory")?;
+    let (reload, _) = broadcast::channel(16);
+    let server = tokio::spawn(serve::serve(build_path, addr, reload.clone()));
 
-    let documents = client
-        .all_documents(false)
-        .await
-        .context("Fetching all document metadata from rM Cloud")?;
-
-    let existing_docs: BTreeMap<Uuid, manifest::DocumentMeta> =
-        if let Ok(existing_manifest) = Manifest::load(&output_path).context("Loading manifest") {
-            existing_manifest
-                .docs()
-                .into_iter()
-                .map(|d| (d.id, d.clone()))
-                .collect()
-        } else {
-            Default::default()
-        };
-
-    let manifest =
-        Manifest::build(config.site_root, documents).context("Building Manifest from documents")?;
-
-    for doc in manifest.docs() {
-        if let Some(existing_doc) = existing_docs.get(&doc.id) {
-            if existing_doc.modified_client >= doc.modified_client {
-                println!("Nothing new from {}", doc.id);
-                continue;
-            }
-        }
-        println!("Downloading {}", doc.id);
+    let watcher = tokio::task::spawn_blocking(move || {
+        watch::watch(generator, || {
+            println!("Rebuilt site, reloading browsers");
+            let _ = reload.send(());
+        })
+    });
 
-        let zip = client
-            .download_zip(doc.id)
-            .await
-            .context("Downloading document zip")?;
-        let bytes = zip.into_inner().into_inner();
-        let mut file = std::fs::File::create(&archives_dir.join(format!("{}.zip", doc.id)))
-            .context("Creating file for document zip")?;
-        file.write_all(&bytes)
-            .context("Writing document zip to disk")?;
+    tokio::select! {
+        served = server => served.context("Joining preview server")?,
+        watched = watcher => watched.context("Joining watch task")?,
     }
+}
 
-    manifest
-        .save(&output_path)
-        .context("Saving the generated Manifest")?;
+async fn register(code: String, cloud: &CloudEndpoints) -> Result<()> {
+    let http = cloud
+        .http_client()
+        .context("Building http client for rM cloud")?;
+    let device_token = cloud.device_token(&http, &code).await?;
 
+    let path = Credentials::default_path()?;
+    let mut credentials = Credentials::load(&path).context("Loading credentials")?;
+    credentials.set_device_token(cloud, device_token)?;
+    credentials.save().context("Saving credentials")?;
+    println!("Saved device token to {:?}", credentials.path());
     Ok(())
 }
 
-async fn build_rm_client(device_token: String) -> Result<Client> {
-    let mut client = Client::new(
-        ClientState {
-            device_token,
-            ..Default::default()
-        },
-        reqwest::Client::builder()
-            .user_agent("marker-network-site-generator-cli")
-            .build()
-            .context("Building reqwest client")?,
-    );
-
-    client
-        .refresh_state()
+/// The device token from the credentials store and a user token for it,
+/// reusing the cached one until it expires.
+async fn rm_tokens(cloud: &CloudEndpoints, http: &reqwest::Client) -> Result<(String, String)> {
+    let path = Credentials::default_path()?;
+    let mut credentials = Credentials::load(&path).context("Loading credentials")?;
+    let device_token = credentials.device_token(cloud)?;
+    let user_token = credentials
+        .user_token(cloud, http)
         .await
         .context("Refreshing rM Cloud auth tokens")?;
+    Ok((device_token, user_token))
+}
+
+async fn build_rm_client(cloud: &CloudEndpoints) -> Result<RmCloud> {
+    if !cloud.is_official() {
+        println!("Using self-hosted rM cloud {:?}", cloud);
+    }
+    let http = cloud
+        .resolving_http_client()
+        .await
+        .context("Building http client for rM cloud")?;
+    let (device_token, user_token) = rm_tokens(cloud, &http).await?;
 
-    Ok(client)
+    Ok(RmCloud::new(cloud, http, device_token, user_token))
 }
 
 #[tokio::main]
@@ -211,45 +314,220 @@ async fn main() -> Result<()> {
     let opt = Opt::from_args();
 
     match opt.action {
+        Action::Register { code, cloud } => {
+            register(code, &cloud)
+                .await
+                .context("Registering with rM cloud")?;
+        }
         Action::Init {
-            device_token,
             folder,
+            cloud,
+            starter,
+            dry_run,
         } => {
-            let client = build_rm_client(device_token)
+            let starter = Starter::find(&starter)?;
+            let client = build_rm_client(&cloud)
                 .await
                 .context("Building rM Client")?;
-            init(client, folder, opt.config_path)
+            starter
+                .with_dir(|starter_dir| {
+                    init::init(
+                        &client,
+                        folder,
+                        starter_dir,
+                        cloud,
+                        opt.config_path,
+                        dry_run,
+                    )
+                })
                 .await
                 .context("Initializing site")?;
         }
         Action::Fetch {
-            device_token,
             material_path,
+            usb,
+            sync15,
+            jobs,
+            retries,
         } => {
             let config = Config::load(&opt.config_path).context("Loading site config")?;
-            let client = build_rm_client(device_token)
-                .await
-                .context("Building rM Client")?;
-            fetch(config, client, &material_path)
-                .await
-                .context("Fetching site data")?;
+            let options = FetchOptions { jobs, retries };
+            match usb {
+                Some(host) => {
+                    let usb = UsbClient::new(host.as_deref().unwrap_or(UsbClient::DEFAULT_HOST))
+                        .context("Building USB client")?;
+                    fetch::fetch(config, &usb, &material_path, options)
+                        .await
+                        .context("Fetching site data over USB")?;
+                }
+                None if sync15 => {
+                    let http = config
+                        .cloud
+                        .http_client()
+                        .context("Building http client for rM cloud")?;
+                    let (_, user_token) = rm_tokens(&config.cloud, &http).await?;
+                    let client = SyncClient::new(
+                        http,
+                        &config.cloud,
+                        user_token,
+                        material_path.join("blobs"),
+                    )
+                    .context("Building rM sync client")?;
+                    fetch::fetch(config, &client, &material_path, options)
+                        .await
+                        .context("Fetching site data")?;
+                }
+                None => {
+                    let client = build_rm_client(&config.cloud)
+                        .await
+                        .context("Building rM Client")?;
+                    fetch::fetch(config, &client, &material_path, options)
+                        .await
+                        .context("Fetching site data")?;
+                }
+            }
+        }
+        Action::Import {
+            xochitl_path,
+            material_path,
+        } => {
+            let config = Config::load(&opt.config_path).context("Loading site config")?;
+            let xochitl = Xochitl::load(&xochitl_path).context("Loading xochitl directory")?;
+            xochitl
+                .import(config.site_root, &material_path)
+                .context("Importing site data from xochitl")?;
         }
         Action::Gen {
             material_path,
             build_path,
+            prefix,
+            file_urls,
+            watch,
+            from_dir,
+            dry_run,
         } => {
-            let config = Config::load(&opt.config_path).context("Loading site config")?;
-            let generator = Generator::prepare(
-                config,
+            if let Some(site_dir) = from_dir {
+                Manifest::stage_directory(&site_dir, &material_path)
+                    .context("Building material from local directory")?;
+            }
+            let prefix = if file_urls {
+                std::fs::create_dir_all(&build_path).context("Creating build directory")?;
+                let abs_build_path = build_path
+                    .canonicalize()
+                    .context("Resolving absolute build path")?;
+                PathBuf::from(format!("{}/", abs_build_path.display()))
+            } else {
+                prefix
+            };
+            let generator = gen(
+                &opt.config_path,
+                &material_path,
+                &build_path,
+                prefix,
+                opt.no_cache,
+                dry_run,
+            )?;
+            if watch {
+                watch::watch(generator, || println!("Rebuilt site"))
+                    .context("Watching for changes")?;
+            }
+        }
+        Action::Serve {
+            material_path,
+            build_path,
+            addr,
+        } => {
+            let build_path =
+                build_path.unwrap_or_else(|| std::env::temp_dir().join("marker-site-preview"));
+            preview(
+                opt.config_path,
                 material_path,
                 build_path,
-                PathBuf::from("/"),
+                addr,
                 opt.no_cache,
             )
-            .context("Preparing to generate site")?;
-
-            generator.gen_index().context("Generating site")?;
+            .await
+            .context("Serving site preview")?;
         }
+        Action::Push {
+            path,
+            into,
+            replace,
+            site_page,
+        } => {
+            let config = Config::load(&opt.config_path).context("Loading site config")?;
+            let client = build_rm_client(&config.cloud)
+                .await
+                .context("Building rM Client")?;
+            push::push(&client, &config.site_root, &path, &into, replace, site_page)
+                .await
+                .with_context(|| format!("Pushing {:?}", path))?;
+        }
+        Action::Export {
+            material_path,
+            out_dir,
+            pdf,
+            svg,
+        } => {
+            let config = Config::load(&opt.config_path).context("Loading site config")?;
+            let cache_dir = config.cache_dir.unwrap_or_else(RenderCache::default_dir);
+            let cache =
+                RenderCache::open(cache_dir, opt.no_cache).context("Opening render cache")?;
+            export::export(&material_path, &out_dir, &cache, ExportOptions { pdf, svg })
+                .context("Exporting site material")?;
+        }
+        Action::Cache { action } => {
+            let config = Config::load(&opt.config_path).context("Loading site config")?;
+            let cache_dir = config.cache_dir.unwrap_or_else(RenderCache::default_dir);
+            let cache = RenderCache::open_existing(cache_dir).context("Opening render cache")?;
+            match action {
+                CacheAction::Verify => {
+                    let report = cache.verify().context("Verifying render cache")?;
+                    println!(
+                        "{} valid entries, removed {} in {:?}",
+                        report.valid,
+                        report.removed,
+                        cache.dir()
+                    );
+                }
+                CacheAction::Clear => {
+                    let removed = cache.clear().context("Clearing render cache")?;
+                    println!("Removed {} entries from {:?}", removed, cache.dir());
+                }
+            }
+        }
+        Action::Starter { action } => match action {
+            StarterAction::List => {
+                for name in Starter::names() {
+                    println!("{}", name);
+                    for file in Starter::files(name)? {
+                        println!("  {}", file.display());
+                    }
+                }
+            }
+            StarterAction::Export { name, out_dir } => {
+                let starter = match Starter::find(&name)? {
+                    Starter::Local(_) => {
+                        return Err(anyhow!("No built-in starter named {:?}", name))
+                    }
+                    embedded => embedded,
+                };
+                starter.export(&out_dir).context("Exporting starter")?;
+                println!("Exported {} to {:?}", name, out_dir);
+            }
+            StarterAction::Package {
+                folder,
+                out_dir,
+                cloud,
+            } => {
+                let client = build_rm_client(&cloud)
+                    .await
+                    .context("Building rM Client")?;
+                Starter::package(&client, &folder, &out_dir)
+                    .await
+                    .context("Packaging starter")?;
+            }
+        },
     };
     Ok(())
 }
@@ -1,10 +1,97 @@
 use std::collections::BTreeMap;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
 use remarkable_cloud_api::{Documents, Parent, Uuid};
 use serde::{Deserialize, Serialize};
 
+/// A folder or document as listed by a material source.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: Uuid,
+    pub visible_name: String,
+    /// Either `"CollectionType"` for folders or `"DocumentType"`
+    pub doc_type: String,
+    pub modified_client: chrono::DateTime<chrono::Utc>,
+}
+
+/// The folder hierarchy that a `Manifest` is built from.
+pub trait DocumentTree {
+    fn node(&self, id: Uuid) -> Option<Node>;
+
+    /// Lists the children of a folder, `None` lists the top level.
+    fn child_nodes(&self, parent: Option<Uuid>) -> Vec<Node>;
+}
+
+impl DocumentTree for Documents {
+    fn node(&self, id: Uuid) -> Option<Node> {
+        self.get(&id).map(|d| Node {
+            id: d.id,
+            visible_name: d.visible_name.clone(),
+            doc_type: d.doc_type.clone(),
+            modified_client: d.modified_client,
+        })
+    }
+
+    fn child_nodes(&self, parent: Option<Uuid>) -> Vec<Node> {
+        let parent = match parent {
+            Some(id) => Parent::Node(id),
+            None => Parent::Root,
+        };
+        self.children(parent)
+            .into_iter()
+            .map(|d| Node {
+                id: d.id,
+                visible_name: d.visible_name.clone(),
+                doc_type: d.doc_type.clone(),
+                modified_client: d.modified_client,
+            })
+            .collect()
+    }
+}
+
+/// A `DocumentTree` assembled from a flat listing of nodes and their parents.
+#[derive(Debug, Default, Clone)]
+pub struct NodeTree {
+    nodes: BTreeMap<Uuid, (Option<Uuid>, Node)>,
+}
+
+impl NodeTree {
+    /// Copies every node reachable from the top level of another tree.
+    pub fn from_tree(tree: &impl DocumentTree) -> Self {
+        let mut nodes = Self::default();
+        let mut to_visit = vec![None];
+        while let Some(parent) = to_visit.pop() {
+            for node in tree.child_nodes(parent) {
+                if node.doc_type == "CollectionType" {
+                    to_visit.push(Some(node.id));
+                }
+                nodes.insert(parent, node);
+            }
+        }
+        nodes
+    }
+
+    pub fn insert(&mut self, parent: Option<Uuid>, node: Node) {
+        self.nodes.insert(node.id, (parent, node));
+    }
+}
+
+impl DocumentTree for NodeTree {
+    fn node(&self, id: Uuid) -> Option<Node> {
+        self.nodes.get(&id).map(|(_, node)| node.clone())
+    }
+
+    fn child_nodes(&self, parent: Option<Uuid>) -> Vec<Node> {
+        self.nodes
+            .values()
+            .filter(|(p, _)| *p == parent)
+            .map(|(_, node)| node.clone())
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocumentMeta {
     pub id: Uuid,
@@ -12,6 +99,68 @@ pub struct DocumentMeta {
     pub modified_client: chrono::DateTime<chrono::Utc>,
 }
 
+impl DocumentMeta {
+    /// Reads the document ID out of a notebook zip (from its `<uuid>.content`
+    /// entry), naming the document after the zip's file stem.
+    fn from_zip(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(std::ffi::OsStr::to_str)
+            .map(String::from)
+            .ok_or_else(|| anyhow!("Zip file has no name: {:?}", path))?;
+
+        let zip_file = std::fs::File::open(path).context("Opening notebook zip file")?;
+        let mut zip = zip::ZipArchive::new(zip_file).context("Reading ZipArchive")?;
+
+        let id = zip
+            .file_names()
+            .filter(|n| !n.contains('/'))
+            .find_map(|n| n.strip_suffix(".content"))
+            .ok_or_else(|| anyhow!("No .content file in notebook zip {:?}", path))?;
+        let id = Uuid::parse_str(id).context("Parsing document ID from .content file name")?;
+
+        let metadata_modified = match zip.by_name(&format!("{}.metadata", id)) {
+            Ok(mut metadata_file) => {
+                let mut buf = String::new();
+                metadata_file
+                    .read_to_string(&mut buf)
+                    .context("Reading .metadata file")?;
+                let metadata: serde_json::Value =
+                    serde_json::from_str(&buf).context("Parsing .metadata file")?;
+                parse_last_modified(&metadata["lastModified"])
+            }
+            Err(_) => None,
+        };
+
+        let modified_client = match metadata_modified {
+            Some(modified) => modified,
+            None => std::fs::metadata(path)
+                .and_then(|m| m.modified())
+                .context("Reading zip file modification time")?
+                .into(),
+        };
+
+        Ok(DocumentMeta {
+            id,
+            name,
+            modified_client,
+        })
+    }
+}
+
+/// `lastModified` in rM metadata files is milliseconds since the epoch,
+/// usually encoded as a string.
+pub(crate) fn parse_last_modified(
+    value: &serde_json::Value,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    use chrono::TimeZone;
+    let millis = match value {
+        serde_json::Value::String(s) => s.parse().ok()?,
+        v => v.as_i64()?,
+    };
+    Some(chrono::Utc.timestamp_millis(millis))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
     pub home: DocumentMeta,
@@ -20,19 +169,32 @@ pub struct Manifest {
 }
 
 impl Manifest {
-    pub fn build(root_folder: String, docs: Documents) -> Result<Self> {
-        let site_root = if let Ok(id) = Uuid::parse_str(&root_folder) {
+    pub fn build(root_folder: String, docs: &impl DocumentTree) -> Result<Self> {
+        let site_root = Self::site_root(&root_folder, docs)?;
+
+        let home = Self::root_doc_by_name("Home", site_root.id, docs)
+            .context("Looking for 'Home' notebook")?;
+        let logo = Self::root_doc_by_name("Logo", site_root.id, docs)
+            .context("Looking for 'Logo' notebook")?;
+        let posts = Posts::build(site_root.id, docs).context("Looking for 'Posts' folder")?;
+
+        Ok(Manifest { home, logo, posts })
+    }
+
+    /// Finds the site folder, given either its ID or the name of a top level folder.
+    pub fn site_root(root_folder: &str, docs: &impl DocumentTree) -> Result<Node> {
+        let site_root = if let Ok(id) = Uuid::parse_str(root_folder) {
             let root_doc = docs
-                .get(&id)
+                .node(id)
                 .ok_or_else(|| anyhow!("No document with ID {}", id))?;
 
             if root_doc.doc_type != "CollectionType" {
                 return Err(anyhow!("Site root must be a folder: {}", root_doc.doc_type));
             }
 
-            root_doc.to_owned()
+            root_doc
         } else {
-            let root_nodes = docs.children(Parent::Root);
+            let root_nodes = docs.child_nodes(None);
             let mut site_roots: Vec<_> = root_nodes
                 .iter()
                 .filter(|d| d.doc_type == "CollectionType")
@@ -49,18 +211,71 @@ impl Manifest {
 
             site_roots.pop().unwrap().to_owned()
         };
+        Ok(site_root)
+    }
 
-        let home = Self::root_doc_by_name("Home", site_root.id, &docs)
-            .context("Looking for 'Home' notebook")?;
-        let logo = Self::root_doc_by_name("Logo", site_root.id, &docs)
-            .context("Looking for 'Logo' notebook")?;
-        let posts = Posts::build(site_root.id, &docs).context("Looking for 'Posts' folder")?;
+    /// Builds a manifest from a local directory laid out like a starter:
+    /// `Home.zip`, `Logo.zip` and a `Posts/` tree of notebook zips. Two zips
+    /// of the same document are an error.
+    pub fn from_directory(dir: &Path) -> Result<Self> {
+        Self::scan_directory(dir, &mut BTreeMap::new())
+    }
+
+    /// Builds a manifest from a local directory and lays it out as material
+    /// (`manifest.json` + `zip/<uuid>.zip`) under `material_root`.
+    pub fn stage_directory(dir: &Path, material_root: &Path) -> Result<Self> {
+        let manifest = Self::from_directory(dir)?;
+
+        // Documents are named after their zip's file stem, so the manifest
+        // leads back to the zips.
+        let mut zips = vec![
+            (manifest.home.id, dir.join("Home.zip")),
+            (manifest.logo.id, dir.join("Logo.zip")),
+        ];
+        for (path, doc) in manifest.posts.docs() {
+            let (name, folders) = path
+                .split_last()
+                .ok_or_else(|| anyhow!("Document {} has no name", doc.id))?;
+            let mut zip_path = dir.join("Posts");
+            zip_path.extend(folders);
+            zip_path.push(format!("{}.zip", name));
+            zips.push((doc.id, zip_path));
+        }
+
+        let archives_dir = material_root.join("zip");
+        std::fs::create_dir_all(&archives_dir).context("Creating zip archives directory")?;
+        for (id, zip_path) in zips {
+            let staged_path = archives_dir.join(format!("{}.zip", id));
+            let _ = std::fs::remove_file(&staged_path);
+            if std::fs::hard_link(&zip_path, &staged_path).is_err() {
+                std::fs::copy(&zip_path, &staged_path)
+                    .with_context(|| format!("Copying {:?} into material", zip_path))?;
+            }
+        }
+
+        manifest
+            .save(material_root)
+            .context("Saving the generated Manifest")?;
+        Ok(manifest)
+    }
+
+    fn scan_directory(dir: &Path, zips: &mut BTreeMap<Uuid, PathBuf>) -> Result<Self> {
+        let home_path = dir.join("Home.zip");
+        let home = DocumentMeta::from_zip(&home_path).context("Reading 'Home' notebook")?;
+        insert_zip(zips, home.id, home_path)?;
+
+        let logo_path = dir.join("Logo.zip");
+        let logo = DocumentMeta::from_zip(&logo_path).context("Reading 'Logo' notebook")?;
+        insert_zip(zips, logo.id, logo_path)?;
+
+        let posts =
+            Posts::scan_directory(&dir.join("Posts"), zips).context("Reading 'Posts' folder")?;
 
         Ok(Manifest { home, logo, posts })
     }
 
     pub fn load(material_root: &Path) -> Result<Self> {
-        let manifest_file = std::fs::File::open(&material_root.join("manifest.json"))
+        let manifest_file = std::fs::File::open(material_root.join("manifest.json"))
             .context("Opening material manifest file")?;
         Self::from_reader(manifest_file)
     }
@@ -71,9 +286,13 @@ impl Manifest {
     }
 
     pub fn save(&self, material_root: &Path) -> Result<()> {
-        let manifest_file = std::fs::File::create(&material_root.join("manifest.json"))
-            .context("Creating manifest file")?;
-        serde_json::to_writer_pretty(manifest_file, &self).context("Writing manifest file")?;
+        let path = material_root.join("manifest.json");
+        let temp_path = path.with_extension("json.tmp");
+        let manifest_file = std::fs::File::create(&temp_path).context("Creating manifest file")?;
+        serde_json::to_writer_pretty(&manifest_file, &self).context("Writing manifest file")?;
+        manifest_file.sync_all().context("Syncing manifest file")?;
+
+        std::fs::rename(temp_path, path).context("Renaming tempfile to manifest file")?;
         Ok(())
     }
 
@@ -84,9 +303,19 @@ impl Manifest {
             .collect()
     }
 
-    fn root_doc_by_name(doc_name: &str, root_id: Uuid, docs: &Documents) -> Result<DocumentMeta> {
+    pub fn docs_mut(&mut self) -> Vec<&mut DocumentMeta> {
+        let mut docs = vec![&mut self.home, &mut self.logo];
+        self.posts.collect_docs_mut(&mut docs);
+        docs
+    }
+
+    fn root_doc_by_name(
+        doc_name: &str,
+        root_id: Uuid,
+        docs: &impl DocumentTree,
+    ) -> Result<DocumentMeta> {
         let mut matching_docs = docs
-            .children(Parent::Node(root_id))
+            .child_nodes(Some(root_id))
             .into_iter()
             .filter(|d| d.visible_name == doc_name && d.doc_type == "DocumentType");
 
@@ -123,9 +352,44 @@ impl Posts {
             .collect()
     }
 
-    fn build(root_id: Uuid, docs: &Documents) -> Result<Posts> {
+    fn collect_docs_mut<'a>(&'a mut self, docs: &mut Vec<&'a mut DocumentMeta>) {
+        docs.extend(self.documents.values_mut());
+        for folder in self.folders.values_mut() {
+            folder.collect_docs_mut(docs);
+        }
+    }
+
+    fn scan_directory(dir: &Path, zips: &mut BTreeMap<Uuid, PathBuf>) -> Result<Posts> {
+        let mut documents = BTreeMap::new();
+        let mut folders = BTreeMap::new();
+
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Listing {:?}", dir))? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(std::ffi::OsStr::to_str)
+                .map(String::from)
+                .ok_or_else(|| anyhow!("Couldn't get file name: {:?}", path))?;
+
+            if path.is_dir() {
+                let sub_folder = Self::scan_directory(&path, zips)?;
+                folders.insert(name, sub_folder);
+            } else if path.extension().and_then(std::ffi::OsStr::to_str) == Some("zip") {
+                let doc = DocumentMeta::from_zip(&path)
+                    .with_context(|| format!("Reading notebook {:?}", path))?;
+                insert_zip(zips, doc.id, path)?;
+                documents.insert(doc.name.clone(), doc);
+            } else {
+                println!("Skipping non-notebook file {:?}", path);
+            }
+        }
+
+        Ok(Posts { documents, folders })
+    }
+
+    fn build(root_id: Uuid, docs: &impl DocumentTree) -> Result<Posts> {
         let mut matching_docs = docs
-            .children(Parent::Node(root_id))
+            .child_nodes(Some(root_id))
             .into_iter()
             .filter(|d| d.visible_name == "Posts" && d.doc_type == "CollectionType");
 
@@ -140,8 +404,8 @@ impl Posts {
         Ok(posts)
     }
 
-    fn build_posts_hierarchy(folder: Uuid, all_docs: &Documents) -> Posts {
-        let items = all_docs.children(Parent::Node(folder));
+    fn build_posts_hierarchy(folder: Uuid, all_docs: &impl DocumentTree) -> Posts {
+        let items = all_docs.child_nodes(Some(folder));
         let documents = items
             .iter()
             .filter(|d| d.doc_type == "DocumentType")
@@ -168,3 +432,18 @@ impl Posts {
         Posts { documents, folders }
     }
 }
+
+/// Records where a document's zip was found, refusing a second zip of the
+/// same document, which would otherwise silently replace the first.
+fn insert_zip(zips: &mut BTreeMap<Uuid, PathBuf>, id: Uuid, path: PathBuf) -> Result<()> {
+    if let Some(existing) = zips.get(&id) {
+        return Err(anyhow!(
+            "{:?} and {:?} are both the document {}",
+            existing,
+            path,
+            id
+        ));
+    }
+    zips.insert(id, path);
+    Ok(())
+}
@@ -0,0 +1,451 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use lines_are_rusty::{BrushType, Color};
+use resvg::usvg::{self, NodeExt, TreeParsing};
+use serde::{Deserialize, Serialize};
+
+use crate::rm6;
+
+/// Size of the tablet's screen in pixels, which is the coordinate space of
+/// the strokes in a `.rm` file.
+pub(crate) const SCREEN_WIDTH: f32 = 1404.0;
+pub(crate) const SCREEN_HEIGHT: f32 = 1872.0;
+/// The tablet's screen is 226 DPI, PDF user space is 72 units per inch.
+const PX_TO_PT: f32 = 72.0 / 226.0;
+
+/// The standard fonts typed text is set in.
+const FONT_RESOURCES: &str = "<< /Font << \
+    /F1 << /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >> \
+    /F2 << /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >> \
+    >> >>";
+
+/// A single rendered notebook page, kept as a PDF content stream in screen
+/// pixel coordinates so documents can be combined into books cheaply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PdfPage {
+    pub content: String,
+    /// The page annotates a page of the document's PDF or EPUB, which
+    /// `content` doesn't include
+    pub background: bool,
+}
+
+impl PdfPage {
+    pub fn from_lines(page: &lines_are_rusty::Page, template: Option<&str>) -> Result<Self> {
+        let mut content = String::new();
+        if let Some(template) = template {
+            content.push_str(&template_background(template)?);
+        }
+
+        content.push_str("1 J 1 j\n");
+        for layer in page.layers.iter() {
+            for line in layer.lines.iter() {
+                match line.brush_type {
+                    BrushType::Eraser | BrushType::EraseArea | BrushType::EraseAll => continue,
+                    BrushType::Highlighter => content.push_str("1 0.92 0.23 RG\n"),
+                    _ => {
+                        let gray = match line.color {
+                            Color::Black => 0.0,
+                            Color::Grey => 0.5,
+                            Color::White => 1.0,
+                        };
+                        let _ = writeln!(content, "{} G", gray);
+                    }
+                }
+
+                for segment in line.points.windows(2) {
+                    let (from, to) = (&segment[0], &segment[1]);
+                    let _ = writeln!(
+                        content,
+                        "{:.2} w {:.2} {:.2} m {:.2} {:.2} l S",
+                        from.width.max(1.0),
+                        from.x,
+                        from.y,
+                        to.x,
+                        to.y
+                    );
+                }
+            }
+        }
+        Ok(Self {
+            content,
+            background: false,
+        })
+    }
+
+    /// Builds a page from a version 6 `.rm` page, typed text is set in the
+    /// standard Helvetica fonts.
+    pub fn from_rm6(page: &rm6::Page, template: Option<&str>) -> Result<Self> {
+        let mut content = String::new();
+        if let Some(template) = template {
+            content.push_str(&template_background(template)?);
+        }
+
+        content.push_str("1 J 1 j\n");
+        for stroke in page.strokes.iter().filter(|s| !s.is_eraser()) {
+            let (r, g, b) = stroke.rgb();
+            let _ = writeln!(
+                content,
+                "{:.3} {:.3} {:.3} RG",
+                r as f32 / 255.0,
+                g as f32 / 255.0,
+                b as f32 / 255.0
+            );
+            for segment in stroke.points.windows(2) {
+                let _ = writeln!(
+                    content,
+                    "{:.2} w {:.2} {:.2} m {:.2} {:.2} l S",
+                    segment[0].width.max(1.0),
+                    segment[0].x,
+                    segment[0].y,
+                    segment[1].x,
+                    segment[1].y
+                );
+            }
+        }
+        if let Some(text) = &page.text {
+            content.push_str("0 g\n");
+            for (paragraph, line, y) in text.positioned_lines() {
+                // Undo the page's y flip so the glyphs come out upright
+                let _ = writeln!(
+                    content,
+                    "q 1 0 0 -1 {:.2} {:.2} cm BT /{} {} Tf 0 0 Td {} Tj ET Q",
+                    text.x,
+                    y,
+                    if paragraph.is_bold() { "F2" } else { "F1" },
+                    paragraph.font_size(),
+                    pdf_latin1(&line)
+                );
+            }
+        }
+        Ok(Self {
+            content,
+            background: false,
+        })
+    }
+}
+
+/// The svg of one of the tablet's page templates, as lines-are-rusty draws
+/// it under v3 and v5 pages, without any xml declaration so it can be
+/// nested in another svg.
+pub(crate) fn template_svg(template: &str) -> Option<&'static str> {
+    let svg = lines_are_rusty::render::templates::TEMPLATES.get(template)?;
+    Some(svg.find("<svg").map_or(svg, |start| &svg[start..]))
+}
+
+/// Draws a template's svg as PDF paths, scaled to the screen. Only the
+/// solid fills and strokes of its shapes are drawn, which is all the tablet
+/// templates use; gradients, images and text are left out.
+fn template_background(template: &str) -> Result<String> {
+    let svg = match template_svg(template) {
+        Some(svg) => svg,
+        None => return Ok(String::new()),
+    };
+    let tree = usvg::Tree::from_str(svg, &usvg::Options::default())
+        .with_context(|| format!("Parsing the svg of template {:?}", template))?;
+    let to_screen = usvg::utils::view_box_to_transform(
+        tree.view_box.rect,
+        tree.view_box.aspect,
+        usvg::Size::from_wh(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap(),
+    );
+
+    let mut content = String::new();
+    for node in tree.root.descendants() {
+        let kind = node.borrow();
+        let path = match &*kind {
+            usvg::NodeKind::Path(path) if path.visibility == usvg::Visibility::Visible => path,
+            _ => continue,
+        };
+        let fill = path.fill.as_ref().and_then(|fill| match &fill.paint {
+            usvg::Paint::Color(color) => Some((color, fill.rule)),
+            _ => None,
+        });
+        let stroke = path.stroke.as_ref().and_then(|stroke| match &stroke.paint {
+            usvg::Paint::Color(color) => Some((color, stroke.width.get())),
+            _ => None,
+        });
+        let paint = match (fill, stroke) {
+            (None, None) => continue,
+            (Some((_, usvg::FillRule::NonZero)), None) => "f",
+            (Some((_, usvg::FillRule::EvenOdd)), None) => "f*",
+            (None, Some(_)) => "S",
+            (Some((_, usvg::FillRule::NonZero)), Some(_)) => "B",
+            (Some((_, usvg::FillRule::EvenOdd)), Some(_)) => "B*",
+        };
+
+        let ts = to_screen.pre_concat(node.abs_transform());
+        let _ = writeln!(
+            content,
+            "q {} {} {} {} {} {} cm",
+            ts.sx, ts.ky, ts.kx, ts.sy, ts.tx, ts.ty
+        );
+        if let Some((color, _)) = fill {
+            let _ = writeln!(content, "{} rg", pdf_rgb(color));
+        }
+        if let Some((color, width)) = stroke {
+            let _ = writeln!(content, "{} RG {} w", pdf_rgb(color), width);
+        }
+        let mut current = usvg::tiny_skia_path::Point::zero();
+        for segment in path.data.segments() {
+            use usvg::tiny_skia_path::PathSegment;
+            let _ = match segment {
+                PathSegment::MoveTo(p) => {
+                    current = p;
+                    writeln!(content, "{} {} m", p.x, p.y)
+                }
+                PathSegment::LineTo(p) => {
+                    current = p;
+                    writeln!(content, "{} {} l", p.x, p.y)
+                }
+                PathSegment::QuadTo(c, p) => {
+                    // PDF only has cubic curves, a quadratic one is the
+                    // cubic with its control points 2/3 of the way to `c`
+                    let c1 = (
+                        current.x + 2.0 / 3.0 * (c.x - current.x),
+                        current.y + 2.0 / 3.0 * (c.y - current.y),
+                    );
+                    let c2 = (p.x + 2.0 / 3.0 * (c.x - p.x), p.y + 2.0 / 3.0 * (c.y - p.y));
+                    current = p;
+                    writeln!(
+                        content,
+                        "{} {} {} {} {} {} c",
+                        c1.0, c1.1, c2.0, c2.1, p.x, p.y
+                    )
+                }
+                PathSegment::CubicTo(c1, c2, p) => {
+                    current = p;
+                    writeln!(
+                        content,
+                        "{} {} {} {} {} {} c",
+                        c1.x, c1.y, c2.x, c2.y, p.x, p.y
+                    )
+                }
+                PathSegment::Close => writeln!(content, "h"),
+            };
+        }
+        let _ = writeln!(content, "{} Q", paint);
+    }
+    Ok(content)
+}
+
+fn pdf_rgb(color: &usvg::Color) -> String {
+    format!(
+        "{:.3} {:.3} {:.3}",
+        color.red as f32 / 255.0,
+        color.green as f32 / 255.0,
+        color.blue as f32 / 255.0
+    )
+}
+
+/// An entry in the PDF's outline, pointing at a page index.
+#[derive(Debug, Clone)]
+pub struct Bookmark {
+    pub title: String,
+    pub page: usize,
+    pub children: Vec<Bookmark>,
+}
+
+/// Writes an uncompressed PDF with one page per `PdfPage` and the given
+/// bookmarks as its outline.
+pub fn write_pdf(path: &Path, pages: &[PdfPage], bookmarks: &[Bookmark]) -> Result<()> {
+    let mut objects: Vec<Vec<u8>> = Vec::new();
+
+    // Object numbers are 1-based, the catalog and page tree come first.
+    let catalog_id = 1;
+    let pages_id = 2;
+    let first_page_id = 3;
+    let page_ids: Vec<usize> = (0..pages.len()).map(|i| first_page_id + 2 * i).collect();
+    let outlines_id = first_page_id + 2 * pages.len();
+
+    let catalog = if bookmarks.is_empty() {
+        format!("<< /Type /Catalog /Pages {} 0 R >>", pages_id)
+    } else {
+        format!(
+            "<< /Type /Catalog /Pages {} 0 R /Outlines {} 0 R /PageMode /UseOutlines >>",
+            pages_id, outlines_id
+        )
+    };
+    objects.push(catalog.into_bytes());
+
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{} 0 R", id)).collect();
+    objects.push(
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            pages.len()
+        )
+        .into_bytes(),
+    );
+
+    for (page, page_id) in pages.iter().zip(&page_ids) {
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent {} 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources {} /Contents {} 0 R >>",
+                pages_id,
+                SCREEN_WIDTH * PX_TO_PT,
+                SCREEN_HEIGHT * PX_TO_PT,
+                FONT_RESOURCES,
+                page_id + 1
+            )
+            .into_bytes(),
+        );
+        // Flip the y axis and scale screen pixels into points.
+        let stream = format!(
+            "{:.4} 0 0 {:.4} 0 {:.2} cm\n{}",
+            PX_TO_PT,
+            -PX_TO_PT,
+            SCREEN_HEIGHT * PX_TO_PT,
+            page.content
+        );
+        let mut object = format!("<< /Length {} >>\nstream\n", stream.len()).into_bytes();
+        object.extend_from_slice(stream.as_bytes());
+        object.extend_from_slice(b"\nendstream");
+        objects.push(object);
+    }
+
+    if !bookmarks.is_empty() {
+        let mut outline_objects = Vec::new();
+        let (first, last, count) = outline_items(
+            bookmarks,
+            outlines_id,
+            outlines_id + 1,
+            &page_ids,
+            &mut outline_objects,
+        );
+        objects.push(
+            format!(
+                "<< /Type /Outlines /First {} 0 R /Last {} 0 R /Count {} >>",
+                first, last, count
+            )
+            .into_bytes(),
+        );
+        objects.extend(outline_objects.into_iter().map(String::into_bytes));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"%PDF-1.4\n");
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+        out.extend_from_slice(object);
+        out.extend_from_slice(b"\nendobj\n");
+    }
+    let xref_offset = out.len();
+    out.extend_from_slice(format!("xref\n0 {}\n", objects.len() + 1).as_bytes());
+    out.extend_from_slice(b"0000000000 65535 f \n");
+    for offset in offsets {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            catalog_id,
+            xref_offset
+        )
+        .as_bytes(),
+    );
+
+    let mut file = std::fs::File::create(path).context("Creating pdf file")?;
+    file.write_all(&out).context("Writing pdf file")?;
+    Ok(())
+}
+
+/// Lays out one level of the outline starting at object `first_id`, pushing
+/// the objects in order. Returns the first and last item ids and the number
+/// of visible items.
+fn outline_items(
+    bookmarks: &[Bookmark],
+    parent_id: usize,
+    first_id: usize,
+    page_ids: &[usize],
+    objects: &mut Vec<String>,
+) -> (usize, usize, usize) {
+    // Each item is followed by all of its descendants, so compute the id of
+    // every sibling before writing any of them.
+    let mut ids = Vec::with_capacity(bookmarks.len());
+    let mut next_id = first_id;
+    for bookmark in bookmarks {
+        ids.push(next_id);
+        next_id += 1 + count_bookmarks(&bookmark.children);
+    }
+
+    let mut count = 0;
+    for (i, bookmark) in bookmarks.iter().enumerate() {
+        let id = ids[i];
+        let slot = objects.len();
+        objects.push(String::new());
+
+        let mut item = format!(
+            "<< /Title {} /Parent {} 0 R",
+            pdf_text(&bookmark.title),
+            parent_id
+        );
+        if i > 0 {
+            let _ = write!(item, " /Prev {} 0 R", ids[i - 1]);
+        }
+        if let Some(next) = ids.get(i + 1) {
+            let _ = write!(item, " /Next {} 0 R", next);
+        }
+        if let Some(page_id) = page_ids.get(bookmark.page) {
+            let _ = write!(item, " /Dest [{} 0 R /Fit]", page_id);
+        }
+        if !bookmark.children.is_empty() {
+            let (first, last, child_count) =
+                outline_items(&bookmark.children, id, id + 1, page_ids, objects);
+            let _ = write!(
+                item,
+                " /First {} 0 R /Last {} 0 R /Count {}",
+                first, last, child_count
+            );
+            count += child_count;
+        }
+        item.push_str(" >>");
+        objects[slot] = item;
+        count += 1;
+    }
+
+    (ids[0], ids[ids.len() - 1], count)
+}
+
+fn count_bookmarks(bookmarks: &[Bookmark]) -> usize {
+    bookmarks
+        .iter()
+        .map(|b| 1 + count_bookmarks(&b.children))
+        .sum()
+}
+
+/// Encodes a string as a UTF-16BE hex string so any title survives.
+fn pdf_text(s: &str) -> String {
+    let mut hex = String::from("<FEFF");
+    for unit in s.encode_utf16() {
+        let _ = write!(hex, "{:04X}", unit);
+    }
+    hex.push('>');
+    hex
+}
+
+/// Encodes text as a PDF literal string in the standard fonts' encoding,
+/// characters outside latin-1 become `?`.
+fn pdf_latin1(s: &str) -> String {
+    let mut out = String::from("(");
+    for c in s.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if (c as u32) < 0x20 => {}
+            c if (c as u32) < 0x80 => out.push(c),
+            c if (c as u32) < 0x100 => {
+                let _ = write!(out, "\\{:03o}", c as u32);
+            }
+            _ => out.push('?'),
+        }
+    }
+    out.push(')');
+    out
+}
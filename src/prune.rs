@@ -0,0 +1,97 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// The list of files the last build produced, relative to the build root.
+const OUTPUTS_FILE: &str = ".marker-outputs.json";
+
+/// Directories only the generator writes to, anything in them it didn't
+/// produce is fair game even without a previous outputs list.
+const GENERATED_DIRS: &[&str] = &["posts", "svg", "raster", "pdf"];
+
+/// Files older versions of the generator left in the build root.
+const LEGACY_FILES: &[&str] = &["render_cache.json"];
+
+/// Removes every file a previous build produced, or that sits in one of the
+/// generated directories, which isn't in `outputs`. Returns the orphaned
+/// files, which are only listed when `dry_run` is set.
+pub fn prune(root: &Path, outputs: &BTreeSet<PathBuf>, dry_run: bool) -> Result<Vec<PathBuf>> {
+    let mut candidates: BTreeSet<PathBuf> = previous_outputs(root)?
+        .into_iter()
+        .map(|path| root.join(path))
+        .collect();
+    for dir in GENERATED_DIRS {
+        collect_files(&root.join(dir), &mut candidates)?;
+    }
+    candidates.extend(LEGACY_FILES.iter().map(|file| root.join(file)));
+
+    let orphaned: Vec<PathBuf> = candidates
+        .into_iter()
+        .filter(|path| path.exists() && !outputs.contains(path))
+        .collect();
+
+    if dry_run {
+        return Ok(orphaned);
+    }
+
+    for path in orphaned.iter() {
+        println!("Removing orphaned {:?}", path);
+        std::fs::remove_file(path).with_context(|| format!("Removing {:?}", path))?;
+    }
+    for dir in GENERATED_DIRS {
+        remove_empty_dirs(&root.join(dir))?;
+    }
+
+    let relative: BTreeSet<&Path> = outputs
+        .iter()
+        .filter_map(|path| path.strip_prefix(root).ok())
+        .collect();
+    let temp_path = root.join(format!("{}.tmp", OUTPUTS_FILE));
+    let file = std::fs::File::create(&temp_path).context("Creating outputs list")?;
+    serde_json::to_writer_pretty(file, &relative).context("Writing outputs list")?;
+    std::fs::rename(temp_path, root.join(OUTPUTS_FILE)).context("Renaming outputs list")?;
+
+    Ok(orphaned)
+}
+
+fn previous_outputs(root: &Path) -> Result<Vec<PathBuf>> {
+    let path = root.join(OUTPUTS_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(path).context("Opening outputs list")?;
+    serde_json::from_reader(file).context("Parsing outputs list")
+}
+
+fn collect_files(dir: &Path, files: &mut BTreeSet<PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Listing {:?}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Removes directories left empty under `dir`, keeping `dir` itself.
+fn remove_empty_dirs(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Listing {:?}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+            if std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path).with_context(|| format!("Removing {:?}", path))?;
+            }
+        }
+    }
+    Ok(())
+}
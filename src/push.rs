@@ -0,0 +1,116 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use remarkable_cloud_api::Uuid;
+
+use crate::init::{document_type, sync_directory, upload_file, SyncOptions};
+use crate::manifest::{DocumentTree, Manifest, NodeTree};
+use crate::source::CloudBackend;
+
+/// Uploads `path` into the folder `into` of the site, a `/` separated path of
+/// folder names from the site root such as `Posts/Drafts`. Folders along the
+/// way are created as needed.
+///
+/// A file is uploaded as a single document, a directory as a folder of the
+/// same name with everything in it. Documents whose name is already taken
+/// are only replaced with `replace`, which deletes every document of that
+/// name. The site's `Home` and `Logo` at the top of the site folder are only
+/// pushed with `site_page`.
+pub async fn push(
+    backend: &impl CloudBackend,
+    site_root: &str,
+    path: &Path,
+    into: &str,
+    replace: bool,
+    site_page: bool,
+) -> Result<()> {
+    let docs = backend
+        .list_documents()
+        .await
+        .context("Fetching all document metadata")?;
+    let site_root = Manifest::site_root(site_root, &docs)?;
+
+    let mut folder_id = site_root.id;
+    for name in into.split('/').filter(|name| !name.is_empty()) {
+        folder_id = ensure_folder(backend, &docs, folder_id, name).await?;
+    }
+
+    if path.is_dir() {
+        let name = path
+            .file_name()
+            .and_then(std::ffi::OsStr::to_str)
+            .ok_or_else(|| anyhow!("Couldn't get folder name: {:?}", path))?;
+        let folder_id = ensure_folder(backend, &docs, folder_id, name).await?;
+        let options = SyncOptions {
+            dry_run: false,
+            replace,
+            site_root: false,
+        };
+        return sync_directory(backend, &docs, path, Some(folder_id), options, 1).await;
+    }
+
+    let file_type = document_type(path)
+        .ok_or_else(|| anyhow!("Only .zip, .pdf and .epub files can be pushed: {:?}", path))?;
+    let name = path
+        .file_stem()
+        .and_then(std::ffi::OsStr::to_str)
+        .map(String::from)
+        .ok_or_else(|| anyhow!("Document has no name: {:?}", path))?;
+    if folder_id == site_root.id && ["Home", "Logo"].contains(&name.as_str()) && !site_page {
+        return Err(anyhow!(
+            "'{}' at the top of the site folder is the site's {} page, pass --site-page to push it there",
+            name,
+            name.to_lowercase()
+        ));
+    }
+
+    let existing: Vec<Uuid> = docs
+        .child_nodes(Some(folder_id))
+        .into_iter()
+        .filter(|n| n.visible_name == name && n.doc_type == "DocumentType")
+        .map(|n| n.id)
+        .collect();
+    if !existing.is_empty() && !replace {
+        return Err(anyhow!(
+            "There already is a document named '{}' in '{}', pass --replace to replace it",
+            name,
+            into
+        ));
+    }
+
+    println!("Uploading {:?}", path);
+    upload_file(backend, path, file_type, name, folder_id).await?;
+    for id in existing {
+        println!("Deleting the replaced document {}", id);
+        backend
+            .delete(id)
+            .await
+            .context("Deleting replaced document")?;
+    }
+    Ok(())
+}
+
+/// The folder named `name` in `parent`, created if there isn't one.
+async fn ensure_folder(
+    backend: &impl CloudBackend,
+    docs: &NodeTree,
+    parent: Uuid,
+    name: &str,
+) -> Result<Uuid> {
+    let mut matching = docs
+        .child_nodes(Some(parent))
+        .into_iter()
+        .filter(|n| n.visible_name == name && n.doc_type == "CollectionType");
+
+    match (matching.next(), matching.next()) {
+        (Some(folder), None) => Ok(folder.id),
+        (None, _) => {
+            println!("Creating folder {:?}", name);
+            backend
+                .create_folder(Uuid::new_v4(), name.to_string(), Some(parent))
+                .await
+                .context("Creating folder on remarkable")
+        }
+        (Some(_), Some(_)) => Err(anyhow!("Multiple '{}' folders", name)),
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use resvg::tiny_skia;
+use resvg::usvg::{self, TreeParsing};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RasterFormat {
+    Png,
+    Webp,
+}
+
+impl RasterFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RasterFormat::Png => "png",
+            RasterFormat::Webp => "webp",
+        }
+    }
+}
+
+/// A parsed svg page that can be rendered to images of any width.
+pub struct Rasterizer {
+    tree: resvg::Tree,
+}
+
+impl Rasterizer {
+    pub fn load(svg_path: &Path) -> Result<Self> {
+        let svg = std::fs::read(svg_path).context("Reading svg to rasterize")?;
+        let usvg_tree =
+            usvg::Tree::from_data(&svg, &usvg::Options::default()).context("Parsing svg")?;
+        Ok(Self {
+            tree: resvg::Tree::from_usvg(&usvg_tree),
+        })
+    }
+
+    /// Renders the page scaled to `width` pixels wide, keeping its aspect ratio.
+    pub fn render(&self, width: u32, format: RasterFormat, out: &Path) -> Result<()> {
+        let size = self.tree.size;
+        let scale = width as f32 / size.width();
+        let height = (size.height() * scale).ceil().max(1.0) as u32;
+        let mut pixmap = tiny_skia::Pixmap::new(width, height)
+            .ok_or_else(|| anyhow!("Invalid raster size {}x{}", width, height))?;
+        pixmap.fill(tiny_skia::Color::WHITE);
+        self.tree.render(
+            tiny_skia::Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut(),
+        );
+
+        match format {
+            RasterFormat::Png => pixmap.save_png(out).context("Writing png")?,
+            RasterFormat::Webp => {
+                let rgba: Vec<u8> = pixmap
+                    .pixels()
+                    .iter()
+                    .flat_map(|p| {
+                        let c = p.demultiply();
+                        [c.red(), c.green(), c.blue(), c.alpha()]
+                    })
+                    .collect();
+                let file = std::fs::File::create(out).context("Creating webp file")?;
+                image::codecs::webp::WebPEncoder::new_lossless(std::io::BufWriter::new(file))
+                    .encode(&rgba, width, height, image::ExtendedColorType::Rgba8)
+                    .context("Encoding webp")?;
+            }
+        }
+        Ok(())
+    }
+}
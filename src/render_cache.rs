@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Bumped whenever rendering changes in a way that should invalidate every
+/// cached page, crate releases that don't touch rendering keep the cache.
+/// Updates of lines-are-rusty are covered by its locked revision.
+const RENDERER_VERSION: u32 = 3;
+
+/// Written by `RenderCache::open`, so `cache verify` and `cache clear` never
+/// touch a directory that isn't a render cache. Follows the Cache Directory
+/// Tagging Specification, which also keeps backup tools out of the cache.
+const MARKER_FILE: &str = "CACHEDIR.TAG";
+const MARKER_CONTENTS: &str = "Signature: 8a477f597d28d172789f06886806bc55\n\
+# This file is a render cache tag created by marker-network-site-generator.\n";
+
+/// Everything besides the zip itself that changes how a document renders.
+#[derive(Debug, Serialize)]
+pub struct RenderSettings {
+    pub renderer: u32,
+    pub lines_are_rusty: &'static str,
+    pub scale: f32,
+    pub crop: bool,
+    pub colors: &'static str,
+}
+
+impl RenderSettings {
+    pub fn new(scale: f32, crop: bool) -> Self {
+        Self {
+            renderer: RENDERER_VERSION,
+            lines_are_rusty: env!("LINES_ARE_RUSTY_REV"),
+            scale,
+            crop,
+            colors: "default",
+        }
+    }
+}
+
+/// Rendered documents keyed on a hash of their zip and the render settings,
+/// kept outside of the build directory so they survive it being deleted.
+///
+/// Each entry is a directory holding `svg/<n>.svg`, `pdf.json` and any
+/// rasterized pages under `raster/`, plus an `entry.json` index of the files
+/// it was rendered with.
+pub struct RenderCache {
+    dir: PathBuf,
+    refresh: bool,
+}
+
+impl RenderCache {
+    /// With `refresh` every document is re-rendered, overwriting its entry.
+    pub fn open(dir: PathBuf, refresh: bool) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Creating render cache directory {:?}", dir))?;
+        let marker = dir.join(MARKER_FILE);
+        if !marker.exists() {
+            std::fs::write(&marker, MARKER_CONTENTS)
+                .with_context(|| format!("Writing {:?}", marker))?;
+        }
+        Ok(Self { dir, refresh })
+    }
+
+    /// Opens a cache an earlier build created, refusing directories without
+    /// the marker `open` writes.
+    pub fn open_existing(dir: PathBuf) -> Result<Self> {
+        if !dir.join(MARKER_FILE).is_file() {
+            return Err(anyhow!(
+                "{:?} is not a render cache, it has no {}",
+                dir,
+                MARKER_FILE
+            ));
+        }
+        Ok(Self {
+            dir,
+            refresh: false,
+        })
+    }
+
+    /// `$XDG_CACHE_HOME/marker-network-site-generator`, falling back to
+    /// `~/.cache` and then the system temp directory.
+    pub fn default_dir() -> PathBuf {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        base.join("marker-network-site-generator")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn key(zip: &[u8], settings: &RenderSettings) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(zip);
+        hasher.update(serde_json::to_vec(settings).context("Serializing render settings")?);
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+
+    /// The entry for `key`, if it has been rendered. Entries that fail
+    /// validation are dropped so the document gets re-rendered.
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let entry = self.dir.join(key);
+        if self.refresh || !entry.is_dir() {
+            return None;
+        }
+        match verify_entry(&entry) {
+            Ok(()) => Some(entry),
+            Err(e) => {
+                println!("Dropping render cache entry {}: {:#}", key, e);
+                let _ = std::fs::remove_dir_all(&entry);
+                None
+            }
+        }
+    }
+
+    /// Validates every entry, removing broken ones and leftovers from
+    /// interrupted renders. Files not named like an entry are left alone.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for path in self.entry_paths()? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !path.is_dir() || name.ends_with(".tmp") {
+                println!("Removing leftover {:?}", path);
+                remove_path(&path)?;
+                report.removed += 1;
+                continue;
+            }
+            match verify_entry(&path) {
+                Ok(()) => report.valid += 1,
+                Err(e) => {
+                    println!("Removing broken entry {}: {:#}", name, e);
+                    remove_path(&path)?;
+                    report.removed += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Removes every entry, returning how many there were.
+    pub fn clear(&self) -> Result<usize> {
+        let mut removed = 0;
+        for path in self.entry_paths()? {
+            remove_path(&path)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Paths in the cache named like an entry or an entry being rendered:
+    /// a hex sha256 key, optionally followed by `.tmp`.
+    fn entry_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.dir).context("Listing render cache")? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let key = name.strip_suffix(".tmp").unwrap_or(&name);
+            if key.len() == 64 && key.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Renders a new entry into a temporary directory and moves it into
+    /// place once `render` succeeds, so a crash never leaves half an entry.
+    pub fn insert_with(
+        &self,
+        key: &str,
+        render: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<PathBuf> {
+        let entry = self.dir.join(key);
+        let temp = self.dir.join(format!("{}.tmp", key));
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).context("Creating render cache entry")?;
+
+        if let Err(e) = render(&temp).and_then(|()| EntryIndex::write(&temp)) {
+            let _ = std::fs::remove_dir_all(&temp);
+            return Err(e);
+        }
+
+        let _ = std::fs::remove_dir_all(&entry);
+        std::fs::rename(&temp, &entry).context("Moving render cache entry into place")?;
+        Ok(entry)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub valid: usize,
+    pub removed: usize,
+}
+
+/// The files an entry was rendered with and their sizes.
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryIndex {
+    pages: usize,
+    files: BTreeMap<String, u64>,
+}
+
+impl EntryIndex {
+    fn write(entry: &Path) -> Result<()> {
+        let mut files = BTreeMap::new();
+        for svg in entry_svgs(entry)? {
+            let name = format!("svg/{}", svg.file_name().unwrap().to_string_lossy());
+            files.insert(name, file_size(&svg)?);
+        }
+        let pages = files.len();
+        files.insert("pdf.json".to_string(), file_size(&entry.join("pdf.json"))?);
+
+        let index = Self { pages, files };
+        let file =
+            std::fs::File::create(entry.join("entry.json")).context("Creating entry index")?;
+        serde_json::to_writer_pretty(file, &index).context("Writing entry index")?;
+        Ok(())
+    }
+}
+
+/// Checks an entry holds the pages its index lists, with the same non-zero
+/// sizes.
+fn verify_entry(entry: &Path) -> Result<()> {
+    let file = std::fs::File::open(entry.join("entry.json")).context("Opening entry index")?;
+    let index: EntryIndex = serde_json::from_reader(file).context("Parsing entry index")?;
+
+    for (name, size) in index.files.iter() {
+        let actual = file_size(&entry.join(name))?;
+        if actual == 0 || actual != *size {
+            return Err(anyhow!("{} is {} bytes, expected {}", name, actual, size));
+        }
+    }
+    if entry_svgs(entry)?.len() != index.pages {
+        return Err(anyhow!("Expected {} pages", index.pages));
+    }
+    Ok(())
+}
+
+/// The page svg's of an entry in page order, ignoring anything that isn't
+/// named `<n>.svg`.
+pub fn entry_svgs(entry: &Path) -> Result<Vec<PathBuf>> {
+    let mut svgs = Vec::new();
+    for svg in std::fs::read_dir(entry.join("svg")).context("Listing rendered svg's")? {
+        let path = svg?.path();
+        if path.extension().and_then(std::ffi::OsStr::to_str) != Some("svg") {
+            continue;
+        }
+        let page = path
+            .file_stem()
+            .and_then(std::ffi::OsStr::to_str)
+            .and_then(|stem| stem.parse::<usize>().ok());
+        if let Some(page) = page {
+            svgs.push((page, path));
+        }
+    }
+    svgs.sort();
+    Ok(svgs.into_iter().map(|(_, path)| path).collect())
+}
+
+fn file_size(path: &Path) -> Result<u64> {
+    Ok(std::fs::metadata(path)
+        .with_context(|| format!("Reading size of {:?}", path))?
+        .len())
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path).with_context(|| format!("Removing {:?}", path))
+    } else {
+        std::fs::remove_file(path).with_context(|| format!("Removing {:?}", path))
+    }
+}
+
+/// Hard-links `src` to `dst`, copying when linking isn't possible (e.g.
+/// across file systems).
+pub fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    let _ = std::fs::remove_file(dst);
+    if std::fs::hard_link(src, dst).is_err() {
+        std::fs::copy(src, dst).with_context(|| format!("Copying {:?} to {:?}", src, dst))?;
+    }
+    Ok(())
+}
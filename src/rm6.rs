@@ -0,0 +1,955 @@
+//! A parser for the block based version 6 `.rm` format written by current
+//! firmware, covering strokes and typed text.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::feed::escape_xml;
+use crate::pdf::{template_svg, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const HEADER_LEN: usize = 43;
+const HEADER_PREFIX: &str = "reMarkable .lines file, version=";
+
+const LINE_ITEM_BLOCK: u8 = 0x05;
+const ROOT_TEXT_BLOCK: u8 = 0x07;
+
+const TAG_BYTE4: u8 = 0x4;
+const TAG_BYTE8: u8 = 0x8;
+const TAG_LENGTH4: u8 = 0xC;
+const TAG_ID: u8 = 0xF;
+
+/// Room left around the content of a cropped page, in screen pixels.
+const CROP_MARGIN: f32 = 10.0;
+
+/// The version from a `.rm` file's header, `None` if it isn't a `.rm` file.
+pub fn version(data: &[u8]) -> Option<u32> {
+    let header = std::str::from_utf8(data.get(..HEADER_LEN)?).ok()?;
+    header.strip_prefix(HEADER_PREFIX)?.trim_end().parse().ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stroke {
+    pub tool: u32,
+    pub color: u32,
+    pub points: Vec<Point>,
+}
+
+impl Stroke {
+    pub fn is_eraser(&self) -> bool {
+        matches!(self.tool, 6 | 8)
+    }
+
+    pub fn is_highlighter(&self) -> bool {
+        matches!(self.tool, 5 | 18)
+    }
+
+    /// The average width of the stroke's points, svg paths can't vary it.
+    pub fn width(&self) -> f32 {
+        if self.points.is_empty() {
+            return 0.0;
+        }
+        self.points.iter().map(|p| p.width).sum::<f32>() / self.points.len() as f32
+    }
+
+    /// The stroke's color as an rgb triple.
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        match self.color {
+            1 | 8 => (125, 125, 125),
+            2 => (255, 255, 255),
+            3 | 9 | 13 => (255, 235, 59),
+            4 | 10 => (0, 160, 80),
+            5 => (255, 105, 180),
+            6 => (30, 90, 220),
+            7 => (220, 30, 30),
+            11 => (0, 190, 220),
+            12 => (200, 0, 200),
+            _ if self.is_highlighter() => (255, 235, 59),
+            _ => (0, 0, 0),
+        }
+    }
+}
+
+/// A typed text paragraph with its style.
+#[derive(Debug, Clone)]
+pub struct Paragraph {
+    pub text: String,
+    pub style: u8,
+}
+
+impl Paragraph {
+    pub fn font_size(&self) -> f32 {
+        match self.style {
+            2 => 52.0,
+            _ => 34.0,
+        }
+    }
+
+    pub fn is_bold(&self) -> bool {
+        matches!(self.style, 2 | 3)
+    }
+
+    /// Text prefixed with the marker of bullet and checkbox styles.
+    pub fn display_text(&self) -> String {
+        match self.style {
+            4 => format!("\u{2022} {}", self.text),
+            5 => format!("    \u{25e6} {}", self.text),
+            6 => format!("\u{2610} {}", self.text),
+            7 => format!("\u{2611} {}", self.text),
+            _ => self.text.clone(),
+        }
+    }
+}
+
+/// The typed text block of a page.
+#[derive(Debug, Clone)]
+pub struct Text {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub paragraphs: Vec<Paragraph>,
+}
+
+impl Text {
+    /// Greedily wraps every paragraph to the block's width, returning each
+    /// line with the paragraph it came from.
+    pub fn lines(&self) -> Vec<(&Paragraph, String)> {
+        let mut lines = Vec::new();
+        for paragraph in self.paragraphs.iter() {
+            // A rough average glyph width for a proportional sans-serif font
+            let max_chars = ((self.width / (paragraph.font_size() * 0.5)) as usize).max(1);
+            let mut line = String::new();
+            for word in paragraph.display_text().split(' ') {
+                let len = line.chars().count();
+                if len > 0 && len + 1 + word.chars().count() > max_chars {
+                    lines.push((paragraph, std::mem::take(&mut line)));
+                }
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.push_str(word);
+            }
+            lines.push((paragraph, line));
+        }
+        lines
+    }
+
+    /// `lines` with the baseline each one is set on.
+    pub fn positioned_lines(&self) -> Vec<(&Paragraph, String, f32)> {
+        let mut y = self.y;
+        self.lines()
+            .into_iter()
+            .map(|(paragraph, line)| {
+                y += paragraph.font_size() * 1.4;
+                (paragraph, line, y)
+            })
+            .collect()
+    }
+}
+
+/// A page of a version 6 `.rm` file, in screen pixel coordinates.
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    pub strokes: Vec<Stroke>,
+    pub text: Option<Text>,
+}
+
+impl Page {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        match version(data) {
+            Some(6) => {}
+            Some(v) => return Err(anyhow!("Not a version 6 .rm file: version {}", v)),
+            None => return Err(anyhow!("Not a .rm file")),
+        }
+
+        let mut page = Self::default();
+        let mut reader = Reader::new(&data[HEADER_LEN..]);
+        while !reader.is_empty() {
+            let length = reader.u32()? as usize;
+            let _unknown = reader.u8()?;
+            let _min_version = reader.u8()?;
+            let current_version = reader.u8()?;
+            let block_type = reader.u8()?;
+            let block = reader.take(length)?;
+
+            let mut block_reader = Reader::new(block);
+            let parsed = match block_type {
+                LINE_ITEM_BLOCK => {
+                    parse_line_item(&mut block_reader, current_version).map(|stroke| {
+                        if let Some(stroke) = stroke {
+                            page.strokes.push(stroke);
+                        }
+                    })
+                }
+                ROOT_TEXT_BLOCK => parse_root_text(&mut block_reader).map(|text| {
+                    page.text = Some(text);
+                }),
+                _ => Ok(()),
+            };
+            parsed.with_context(|| format!("Parsing block of type {:#x}", block_type))?;
+        }
+        Ok(page)
+    }
+
+    /// Writes the page as an svg covering the tablet's screen, or with
+    /// `auto_crop` just the part of it with content. Typed text is kept as
+    /// real svg text so it stays selectable.
+    pub fn render_svg(
+        &self,
+        out: &mut impl Write,
+        template: Option<&str>,
+        auto_crop: bool,
+    ) -> Result<()> {
+        let (x, y, width, height) = match self.bounds() {
+            Some((min_x, min_y, max_x, max_y)) if auto_crop => (
+                min_x - CROP_MARGIN,
+                min_y - CROP_MARGIN,
+                max_x - min_x + 2.0 * CROP_MARGIN,
+                max_y - min_y + 2.0 * CROP_MARGIN,
+            ),
+            _ => (0.0, 0.0, SCREEN_WIDTH, SCREEN_HEIGHT),
+        };
+        writeln!(
+            out,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="{x} {y} {w} {h}">"#,
+            x = x,
+            y = y,
+            w = width,
+            h = height
+        )?;
+        // The same template svg lines-are-rusty draws under v3 and v5 pages
+        if let Some(svg) = template.and_then(template_svg) {
+            writeln!(out, "{}", svg)?;
+        }
+        if let Some(text) = &self.text {
+            writeln!(
+                out,
+                r#"<g font-family="Noto Sans, Helvetica, Arial, sans-serif" fill="black">"#
+            )?;
+            for (paragraph, line, y) in text.positioned_lines() {
+                writeln!(
+                    out,
+                    r#"<text x="{:.1}" y="{:.1}" font-size="{}"{}>{}</text>"#,
+                    text.x,
+                    y,
+                    paragraph.font_size(),
+                    if paragraph.is_bold() {
+                        r#" font-weight="bold""#
+                    } else {
+                        ""
+                    },
+                    escape_xml(&line)
+                )?;
+            }
+            writeln!(out, "</g>")?;
+        }
+        for stroke in self.strokes.iter().filter(|s| !s.is_eraser()) {
+            let (first, rest) = match stroke.points.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+            let mut d = format!("M{:.2} {:.2}", first.x, first.y);
+            // A single point still shows up as a dot thanks to the round cap
+            for point in if rest.is_empty() {
+                std::slice::from_ref(first)
+            } else {
+                rest
+            } {
+                d.push_str(&format!(" L{:.2} {:.2}", point.x, point.y));
+            }
+            let (r, g, b) = stroke.rgb();
+            let opacity = if stroke.is_highlighter() { 0.35 } else { 1.0 };
+            writeln!(
+                out,
+                r#"<path d="{}" fill="none" stroke="rgb({},{},{})" stroke-opacity="{}" stroke-width="{:.2}" stroke-linecap="round" stroke-linejoin="round"/>"#,
+                d,
+                r,
+                g,
+                b,
+                opacity,
+                stroke.width().max(1.0)
+            )?;
+        }
+        writeln!(out, "</svg>")?;
+        Ok(())
+    }
+
+    /// The smallest `(min_x, min_y, max_x, max_y)` box holding the page's
+    /// strokes and text, `None` for an empty page.
+    pub fn bounds(&self) -> Option<(f32, f32, f32, f32)> {
+        let mut boxes = Vec::new();
+        for stroke in self.strokes.iter().filter(|s| !s.is_eraser()) {
+            for point in stroke.points.iter() {
+                let r = point.width.max(1.0) / 2.0;
+                boxes.push((point.x - r, point.y - r, point.x + r, point.y + r));
+            }
+        }
+        if let Some(text) = &self.text {
+            if let Some((paragraph, _, y)) = text.positioned_lines().last() {
+                // Leave room for descenders below the last baseline
+                let bottom = y + paragraph.font_size() * 0.4;
+                boxes.push((text.x, text.y, text.x + text.width, bottom));
+            }
+        }
+        boxes
+            .into_iter()
+            .reduce(|a, b| (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)))
+    }
+}
+
+/// v6 coordinates put x = 0 at the middle of the screen.
+fn screen_x(x: f32) -> f32 {
+    x + SCREEN_WIDTH / 2.0
+}
+
+fn parse_line_item(r: &mut Reader, version: u8) -> Result<Option<Stroke>> {
+    let _parent_id = r.tagged_id(1)?;
+    let _item_id = r.tagged_id(2)?;
+    let _left_id = r.tagged_id(3)?;
+    let _right_id = r.tagged_id(4)?;
+    let deleted_length = r.tagged_u32(5)?;
+    if deleted_length > 0 || !r.has_tag(6, TAG_LENGTH4)? {
+        return Ok(None);
+    }
+
+    let mut item = r.subblock(6)?;
+    let item_type = item.u8()?;
+    if item_type != 3 {
+        return Ok(None);
+    }
+    let tool = item.tagged_u32(1)?;
+    let color = item.tagged_u32(2)?;
+    let _thickness_scale = item.tagged_f64(3)?;
+    let _starting_length = item.tagged_f32(4)?;
+
+    let mut data = item.subblock(5)?;
+    let mut points = Vec::new();
+    while !data.is_empty() {
+        let point = if version == 1 {
+            let x = data.f32()?;
+            let y = data.f32()?;
+            let _speed = data.f32()?;
+            let _direction = data.f32()?;
+            let width = data.f32()?;
+            let _pressure = data.f32()?;
+            Point { x, y, width }
+        } else {
+            let x = data.f32()?;
+            let y = data.f32()?;
+            let _speed = data.u16()?;
+            let width = data.u16()? as f32 / 4.0;
+            let _direction = data.u8()?;
+            let _pressure = data.u8()?;
+            Point { x, y, width }
+        };
+        points.push(Point {
+            x: screen_x(point.x),
+            ..point
+        });
+    }
+
+    Ok(Some(Stroke {
+        tool,
+        color,
+        points,
+    }))
+}
+
+type CrdtId = (u8, u64);
+
+/// The id the CRDT sequence uses for its start and end.
+const END_ID: CrdtId = (0, 0);
+
+/// A run of characters in the text's CRDT sequence, inserted between the
+/// characters `left` and `right`. Deleted runs only keep their length.
+struct TextItem {
+    id: CrdtId,
+    left: CrdtId,
+    right: CrdtId,
+    deleted_length: u32,
+    text: String,
+}
+
+/// Puts the characters of the text's CRDT sequence in order.
+///
+/// Every character has to come after the one to its left and before the one
+/// to its right when it was inserted, which is a topological sort over the
+/// characters. Characters inserted in the same place are ordered by id.
+fn order_text(items: Vec<TextItem>) -> Result<Vec<(CrdtId, Option<char>)>> {
+    // Split the runs into single characters, deleted ones have no text
+    let mut chars: BTreeMap<CrdtId, (CrdtId, CrdtId, Option<char>)> = BTreeMap::new();
+    for item in items {
+        let text: Vec<Option<char>> = if item.deleted_length > 0 {
+            vec![None; item.deleted_length as usize]
+        } else {
+            item.text.chars().map(Some).collect()
+        };
+        let len = text.len() as u64;
+        for (i, c) in text.into_iter().enumerate() {
+            let i = i as u64;
+            let id = (item.id.0, item.id.1 + i);
+            let left = if i == 0 {
+                item.left
+            } else {
+                (item.id.0, item.id.1 + i - 1)
+            };
+            let right = if i + 1 == len {
+                item.right
+            } else {
+                (item.id.0, item.id.1 + i + 1)
+            };
+            chars.insert(id, (left, right, c));
+        }
+    }
+
+    // Edges run from every character to those that have to come after it,
+    // links to the sequence's ends or unknown characters add none.
+    let mut after: BTreeMap<CrdtId, Vec<CrdtId>> = BTreeMap::new();
+    let mut before_count: BTreeMap<CrdtId, usize> = chars.keys().map(|id| (*id, 0)).collect();
+    for (id, (left, right, _)) in chars.iter() {
+        for (first, second) in [(*left, *id), (*id, *right)] {
+            if first != END_ID
+                && second != END_ID
+                && chars.contains_key(&first)
+                && chars.contains_key(&second)
+            {
+                after.entry(first).or_default().push(second);
+                *before_count.get_mut(&second).unwrap() += 1;
+            }
+        }
+    }
+
+    let mut ready: BTreeSet<CrdtId> = before_count
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(id, _)| *id)
+        .collect();
+    let mut ordered = Vec::with_capacity(chars.len());
+    while let Some(id) = ready.iter().next().copied() {
+        ready.remove(&id);
+        ordered.push((id, chars[&id].2));
+        for next in after.remove(&id).unwrap_or_default() {
+            let count = before_count.get_mut(&next).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(next);
+            }
+        }
+    }
+
+    if ordered.len() != chars.len() {
+        return Err(anyhow!(
+            "Text sequence is inconsistent, {} of {} characters can't be placed",
+            chars.len() - ordered.len(),
+            chars.len()
+        ));
+    }
+    Ok(ordered)
+}
+
+fn parse_root_text(r: &mut Reader) -> Result<Text> {
+    let _block_id = r.tagged_id(1)?;
+
+    let mut items = Vec::new();
+    let mut styles: BTreeMap<CrdtId, u8> = BTreeMap::new();
+    {
+        let mut contents = r.subblock(2)?;
+        {
+            let mut outer = contents.subblock(1)?;
+            let mut sequence = outer.subblock(1)?;
+            let count = sequence.varuint()?;
+            for _ in 0..count {
+                let mut item = sequence.subblock(0)?;
+                let id = item.tagged_id(2)?;
+                let left = item.tagged_id(3)?;
+                let right = item.tagged_id(4)?;
+                let deleted_length = item.tagged_u32(5)?;
+                let mut text = String::new();
+                if !item.is_empty() {
+                    let mut value = item.subblock(6)?;
+                    let len = value.varuint()? as usize;
+                    let _is_ascii = value.u8()?;
+                    text = String::from_utf8_lossy(value.take(len)?).into_owned();
+                }
+                items.push(TextItem {
+                    id,
+                    left,
+                    right,
+                    deleted_length,
+                    text,
+                });
+            }
+        }
+        {
+            let mut outer = contents.subblock(2)?;
+            let mut formats = outer.subblock(1)?;
+            let count = formats.varuint()?;
+            for _ in 0..count {
+                let char_id = (formats.u8()?, formats.varuint()?);
+                let _timestamp = formats.tagged_id(1)?;
+                let mut value = formats.subblock(2)?;
+                let _marker = value.u8()?;
+                let style = value.u8()?;
+                styles.insert(char_id, style);
+            }
+        }
+    }
+
+    let (x, y) = {
+        let mut position = r.subblock(3)?;
+        (position.f64()? as f32, position.f64()? as f32)
+    };
+    let width = r.tagged_f32(4)?;
+
+    let chars = order_text(items).context("Ordering text")?;
+
+    // A paragraph's style is keyed by the newline before it, the first
+    // paragraph by the zero id.
+    let mut paragraphs = vec![Paragraph {
+        text: String::new(),
+        style: styles.get(&(0, 0)).copied().unwrap_or(1),
+    }];
+    for (id, c) in chars.into_iter().filter_map(|(id, c)| Some((id, c?))) {
+        if c == '\n' {
+            paragraphs.push(Paragraph {
+                text: String::new(),
+                style: styles.get(&id).copied().unwrap_or(1),
+            });
+        } else {
+            paragraphs.last_mut().unwrap().text.push(c);
+        }
+    }
+
+    Ok(Text {
+        x: screen_x(x),
+        y,
+        width,
+        paragraphs,
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("Unexpected end of data at byte {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn varuint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(anyhow!("Varuint is too long"));
+            }
+        }
+    }
+
+    fn has_tag(&mut self, index: u64, tag_type: u8) -> Result<bool> {
+        if self.is_empty() {
+            return Ok(false);
+        }
+        let pos = self.pos;
+        let tag = self.varuint()?;
+        self.pos = pos;
+        Ok(tag == (index << 4) | tag_type as u64)
+    }
+
+    fn tag(&mut self, index: u64, tag_type: u8) -> Result<()> {
+        let pos = self.pos;
+        let tag = self.varuint()?;
+        if tag != (index << 4) | tag_type as u64 {
+            return Err(anyhow!(
+                "Expected tag {} of type {:#x} at byte {}, found {:#x}",
+                index,
+                tag_type,
+                pos,
+                tag
+            ));
+        }
+        Ok(())
+    }
+
+    fn tagged_id(&mut self, index: u64) -> Result<CrdtId> {
+        self.tag(index, TAG_ID)?;
+        Ok((self.u8()?, self.varuint()?))
+    }
+
+    fn tagged_u32(&mut self, index: u64) -> Result<u32> {
+        self.tag(index, TAG_BYTE4)?;
+        self.u32()
+    }
+
+    fn tagged_f32(&mut self, index: u64) -> Result<f32> {
+        self.tag(index, TAG_BYTE4)?;
+        self.f32()
+    }
+
+    fn tagged_f64(&mut self, index: u64) -> Result<f64> {
+        self.tag(index, TAG_BYTE8)?;
+        self.f64()
+    }
+
+    fn subblock(&mut self, index: u64) -> Result<Reader<'a>> {
+        self.tag(index, TAG_LENGTH4)?;
+        let len = self.u32()? as usize;
+        Ok(Reader::new(self.take(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the parts of the v6 format `Page::parse` reads, to build
+    /// sample pages.
+    #[derive(Default)]
+    struct Writer(Vec<u8>);
+
+    impl Writer {
+        fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+            self.0.extend_from_slice(bytes);
+            self
+        }
+
+        fn varuint(&mut self, mut value: u64) -> &mut Self {
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    self.0.push(byte);
+                    return self;
+                }
+                self.0.push(byte | 0x80);
+            }
+        }
+
+        fn tag(&mut self, index: u64, tag_type: u8) -> &mut Self {
+            self.varuint((index << 4) | tag_type as u64)
+        }
+
+        fn id(&mut self, index: u64, id: CrdtId) -> &mut Self {
+            self.tag(index, TAG_ID).bytes(&[id.0]).varuint(id.1)
+        }
+
+        fn u32(&mut self, index: u64, value: u32) -> &mut Self {
+            self.tag(index, TAG_BYTE4).bytes(&value.to_le_bytes())
+        }
+
+        fn f32(&mut self, index: u64, value: f32) -> &mut Self {
+            self.tag(index, TAG_BYTE4).bytes(&value.to_le_bytes())
+        }
+
+        fn f64(&mut self, index: u64, value: f64) -> &mut Self {
+            self.tag(index, TAG_BYTE8).bytes(&value.to_le_bytes())
+        }
+
+        fn subblock(&mut self, index: u64, contents: &Writer) -> &mut Self {
+            self.tag(index, TAG_LENGTH4)
+                .bytes(&(contents.0.len() as u32).to_le_bytes())
+                .bytes(&contents.0)
+        }
+
+        fn block(&mut self, block_type: u8, version: u8, contents: &Writer) -> &mut Self {
+            self.bytes(&(contents.0.len() as u32).to_le_bytes())
+                .bytes(&[0, 1, version, block_type])
+                .bytes(&contents.0)
+        }
+    }
+
+    fn header() -> Writer {
+        let mut w = Writer::default();
+        w.bytes(format!("{:<43}", format!("{}6", HEADER_PREFIX)).as_bytes());
+        w
+    }
+
+    /// A version 2 line block with `(x, y, width)` points.
+    fn line_block(tool: u32, color: u32, points: &[(f32, f32, f32)]) -> Writer {
+        let mut data = Writer::default();
+        for (x, y, width) in points {
+            data.bytes(&x.to_le_bytes())
+                .bytes(&y.to_le_bytes())
+                .bytes(&0u16.to_le_bytes())
+                .bytes(&((width * 4.0) as u16).to_le_bytes())
+                .bytes(&[0, 0]);
+        }
+        let mut item = Writer::default();
+        item.bytes(&[3])
+            .u32(1, tool)
+            .u32(2, color)
+            .f64(3, 1.0)
+            .f32(4, 0.0)
+            .subblock(5, &data);
+        let mut block = Writer::default();
+        block
+            .id(1, (0, 11))
+            .id(2, (1, 16))
+            .id(3, END_ID)
+            .id(4, END_ID)
+            .u32(5, 0)
+            .subblock(6, &item);
+        block
+    }
+
+    /// A root text block of `(id, left, right, text)` runs and
+    /// `(newline id, style)` paragraph styles.
+    fn text_block(runs: &[(CrdtId, CrdtId, CrdtId, &str)], styles: &[(CrdtId, u8)]) -> Writer {
+        let mut sequence = Writer::default();
+        sequence.varuint(runs.len() as u64);
+        for (id, left, right, text) in runs {
+            let mut value = Writer::default();
+            value
+                .varuint(text.len() as u64)
+                .bytes(&[1])
+                .bytes(text.as_bytes());
+            let mut item = Writer::default();
+            item.id(2, *id)
+                .id(3, *left)
+                .id(4, *right)
+                .u32(5, 0)
+                .subblock(6, &value);
+            sequence.subblock(0, &item);
+        }
+        let mut formats = Writer::default();
+        formats.varuint(styles.len() as u64);
+        for (id, style) in styles {
+            let mut value = Writer::default();
+            value.bytes(&[17, *style]);
+            formats
+                .bytes(&[id.0])
+                .varuint(id.1)
+                .id(1, (1, 1))
+                .subblock(2, &value);
+        }
+        let mut contents = Writer::default();
+        contents
+            .subblock(1, Writer::default().subblock(1, &sequence))
+            .subblock(2, Writer::default().subblock(1, &formats));
+        let mut position = Writer::default();
+        position
+            .bytes(&(-400.0f64).to_le_bytes())
+            .bytes(&100.0f64.to_le_bytes());
+
+        let mut block = Writer::default();
+        block
+            .id(1, (0, 0))
+            .subblock(2, &contents)
+            .subblock(3, &position)
+            .f32(4, 800.0);
+        block
+    }
+
+    fn sample_page() -> Vec<u8> {
+        let mut page = header();
+        page.block(
+            LINE_ITEM_BLOCK,
+            2,
+            &line_block(
+                15,
+                0,
+                &[(-100.0, 300.0, 2.0), (0.0, 350.0, 4.0), (100.0, 300.0, 6.0)],
+            ),
+        )
+        .block(LINE_ITEM_BLOCK, 2, &line_block(6, 0, &[(0.0, 0.0, 8.0)]))
+        .block(
+            ROOT_TEXT_BLOCK,
+            1,
+            &text_block(
+                &[((1, 20), END_ID, END_ID, "Title\nBody text")],
+                &[(END_ID, 2), ((1, 25), 4)],
+            ),
+        );
+        page.0
+    }
+
+    fn render(page: &Page, template: Option<&str>, auto_crop: bool) -> String {
+        let mut svg = Vec::new();
+        page.render_svg(&mut svg, template, auto_crop).unwrap();
+        String::from_utf8(svg).unwrap()
+    }
+
+    #[test]
+    fn reads_the_header_version() {
+        assert_eq!(version(&sample_page()), Some(6));
+        assert_eq!(
+            version(format!("{:<43}", "reMarkable .lines file, version=5").as_bytes()),
+            Some(5)
+        );
+        assert_eq!(
+            version(b"PK\x03\x04 not a lines file at all, just a zip"),
+            None
+        );
+        assert_eq!(version(b"short"), None);
+    }
+
+    #[test]
+    fn parses_a_sample_page() {
+        let page = Page::parse(&sample_page()).unwrap();
+
+        assert_eq!(page.strokes.len(), 2);
+        let stroke = &page.strokes[0];
+        assert_eq!((stroke.tool, stroke.color), (15, 0));
+        let points: Vec<(f32, f32, f32)> =
+            stroke.points.iter().map(|p| (p.x, p.y, p.width)).collect();
+        assert_eq!(
+            points,
+            [
+                (602.0, 300.0, 2.0),
+                (702.0, 350.0, 4.0),
+                (802.0, 300.0, 6.0)
+            ]
+        );
+        assert!(page.strokes[1].is_eraser());
+
+        let text = page.text.as_ref().unwrap();
+        assert_eq!((text.x, text.y, text.width), (302.0, 100.0, 800.0));
+        let paragraphs: Vec<(&str, u8)> = text
+            .paragraphs
+            .iter()
+            .map(|p| (p.text.as_str(), p.style))
+            .collect();
+        assert_eq!(paragraphs, [("Title", 2), ("Body text", 4)]);
+    }
+
+    #[test]
+    fn renders_one_path_per_stroke() {
+        let page = Page::parse(&sample_page()).unwrap();
+        let svg = render(&page, None, false);
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(r#"viewBox="0 0 1404 1872""#));
+        // The eraser stroke isn't drawn
+        assert_eq!(svg.matches("<path").count(), 1);
+        assert!(svg.contains(r#"d="M602.00 300.00 L702.00 350.00 L802.00 300.00""#));
+        assert!(svg.contains(r#"stroke-width="4.00""#));
+        assert!(!svg.contains("<line"));
+        assert!(svg.contains(">Title</text>"));
+        assert!(svg.contains(r#"font-weight="bold">Title"#));
+        assert!(svg.contains(">\u{2022} Body text</text>"));
+    }
+
+    #[test]
+    fn crops_to_the_content() {
+        let mut page = header();
+        page.block(
+            LINE_ITEM_BLOCK,
+            2,
+            &line_block(15, 0, &[(-100.0, 300.0, 2.0), (100.0, 500.0, 2.0)]),
+        );
+        let page = Page::parse(&page.0).unwrap();
+
+        assert_eq!(page.bounds(), Some((601.0, 299.0, 803.0, 501.0)));
+        let svg = render(&page, None, true);
+        assert!(svg.contains(r#"width="222" height="222" viewBox="591 289 222 222""#));
+        assert!(render(&Page::default(), None, true).contains(r#"viewBox="0 0 1404 1872""#));
+    }
+
+    #[test]
+    fn draws_the_template() {
+        let page = Page::parse(&sample_page()).unwrap();
+        let svg = render(&page, Some("P Lines medium"), false);
+        let template = template_svg("P Lines medium").unwrap();
+        assert!(template.starts_with("<svg"));
+        // Nested right after the page's own svg element, under the strokes
+        let (_, body) = svg.split_once('\n').unwrap();
+        assert!(body.starts_with(template));
+        assert_eq!(
+            render(&page, Some("No such template"), false),
+            render(&page, None, false)
+        );
+    }
+
+    fn text_item(id: CrdtId, left: CrdtId, right: CrdtId, text: &str) -> TextItem {
+        TextItem {
+            id,
+            left,
+            right,
+            deleted_length: 0,
+            text: text.to_string(),
+        }
+    }
+
+    fn ordered_text(items: Vec<TextItem>) -> Result<String> {
+        Ok(order_text(items)?
+            .into_iter()
+            .filter_map(|(_, c)| c)
+            .collect())
+    }
+
+    #[test]
+    fn orders_text_edits() {
+        let items = vec![
+            // Typed after the deleted run
+            text_item((1, 30), (1, 20), END_ID, "!"),
+            // Inserted in the middle of the first run, between 'e' and 'o'
+            text_item((1, 10), (1, 2), (1, 3), "ll"),
+            TextItem {
+                deleted_length: 1,
+                ..text_item((1, 20), (1, 3), END_ID, "")
+            },
+            text_item((1, 1), END_ID, END_ID, "Heo"),
+        ];
+        assert_eq!(ordered_text(items).unwrap(), "Hello!");
+    }
+
+    #[test]
+    fn rejects_inconsistent_text() {
+        let items = vec![
+            text_item((1, 1), (1, 2), END_ID, "a"),
+            text_item((1, 2), (1, 1), END_ID, "b"),
+        ];
+        assert!(ordered_text(items).is_err());
+    }
+}
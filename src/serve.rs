@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+const RELOAD_ENDPOINT: &str = "/__marker_reload";
+
+const RELOAD_SCRIPT: &str = r#"<script>
+  new EventSource("/__marker_reload").onmessage = function () {
+    window.location.reload();
+  };
+</script>
+"#;
+
+/// Serves the generated site over HTTP and tells open browser tabs to reload
+/// whenever a message is sent on the `reload` channel.
+pub async fn serve(root: PathBuf, addr: SocketAddr, reload: broadcast::Sender<()>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Binding preview server to {}", addr))?;
+    println!("Serving {:?} at http://{}", root, addr);
+
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .context("Accepting preview connection")?;
+        let root = root.clone();
+        let reload = reload.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &root, reload).await {
+                eprintln!("Preview request failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    root: &Path,
+    mut reload: broadcast::Receiver<()>,
+) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.context("Reading request")?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let target = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/");
+    let path = percent_decode(target.split('?').next().unwrap_or("/"));
+
+    if path == RELOAD_ENDPOINT {
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
+            )
+            .await?;
+        loop {
+            match reload.recv().await {
+                Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    stream.write_all(b"data: reload\n\n").await?;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    match resolve(root, &path) {
+        Some(file) => {
+            let mut body = tokio::fs::read(&file)
+                .await
+                .with_context(|| format!("Reading {:?}", file))?;
+            let content_type = content_type(&file);
+            if content_type.starts_with("text/html") {
+                body = inject_reload_script(&body);
+            }
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await?;
+            stream.write_all(&body).await?;
+        }
+        None => {
+            let body = format!("Not found: {}", path);
+            let header = format!(
+                "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await?;
+            stream.write_all(body.as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Maps a request path onto a file under `root`, refusing anything that
+/// would escape the site directory.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = root.to_path_buf();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            s => file.push(s),
+        }
+    }
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    if file.is_file() {
+        Some(file)
+    } else {
+        None
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(std::ffi::OsStr::to_str) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("svg") => "image/svg+xml",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("pdf") => "application/pdf",
+        Some("xml") => "application/xml",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn inject_reload_script(html: &[u8]) -> Vec<u8> {
+    let html = String::from_utf8_lossy(html);
+    match html.rfind("</body>") {
+        Some(idx) => format!("{}{}{}", &html[..idx], RELOAD_SCRIPT, &html[idx..]),
+        None => format!("{}{}", html, RELOAD_SCRIPT),
+    }
+    .into_bytes()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,46 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::feed::escape_xml;
+
+/// A page listed in the sitemap, with an absolute url.
+pub struct SitemapEntry {
+    pub loc: String,
+    pub lastmod: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+pub fn write_sitemap(path: &Path, entries: &[SitemapEntry]) -> Result<()> {
+    let mut out = std::fs::File::create(path).context("Creating sitemap.xml")?;
+    writeln!(out, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#
+    )?;
+    for entry in entries {
+        writeln!(out, "  <url>")?;
+        writeln!(out, "    <loc>{}</loc>", escape_xml(&entry.loc))?;
+        if let Some(lastmod) = entry.lastmod {
+            writeln!(
+                out,
+                "    <lastmod>{}</lastmod>",
+                lastmod.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            )?;
+        }
+        writeln!(out, "  </url>")?;
+    }
+    writeln!(out, "</urlset>")?;
+    Ok(())
+}
+
+pub fn write_robots(path: &Path, sitemap_url: Option<&str>) -> Result<()> {
+    let mut out = std::fs::File::create(path).context("Creating robots.txt")?;
+    writeln!(out, "User-agent: *")?;
+    writeln!(out, "Allow: /")?;
+    if let Some(sitemap_url) = sitemap_url {
+        writeln!(out)?;
+        writeln!(out, "Sitemap: {}", sitemap_url)?;
+    }
+    Ok(())
+}
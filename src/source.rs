@@ -0,0 +1,199 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use remarkable_cloud_api::{reqwest, Client, ClientState, Parent, Uuid};
+use serde::Deserialize;
+
+use crate::cloud::CloudEndpoints;
+use crate::manifest::NodeTree;
+use crate::usb::UsbClient;
+
+const DOCS_URL: &str = "https://document-storage-production-dot-remarkable-production.appspot.com/document-storage/json/2/docs";
+
+const DELETE_URL: &str = "https://document-storage-production-dot-remarkable-production.appspot.com/document-storage/json/2/delete";
+
+/// Anywhere site material can be fetched from.
+#[async_trait]
+pub trait MaterialSource: Send + Sync {
+    /// Lists every folder and document visible to the source.
+    async fn list_documents(&self) -> Result<NodeTree>;
+
+    /// Downloads a document as a zip laid out like the ones the rM cloud serves.
+    async fn download_zip(&self, id: Uuid) -> Result<Vec<u8>>;
+}
+
+/// A material source that can also be written to, used to set up sites.
+#[async_trait]
+pub trait CloudBackend: MaterialSource {
+    async fn create_folder(&self, id: Uuid, name: String, parent: Option<Uuid>) -> Result<Uuid>;
+
+    async fn upload_notebook(
+        &self,
+        id: Uuid,
+        name: String,
+        parent: Option<Uuid>,
+        zip: Vec<u8>,
+    ) -> Result<Uuid>;
+
+    /// Deletes a document.
+    async fn delete(&self, id: Uuid) -> Result<()>;
+}
+
+fn to_parent(parent: Option<Uuid>) -> Parent {
+    match parent {
+        Some(id) => Parent::Node(id),
+        None => Parent::Root,
+    }
+}
+
+#[async_trait]
+impl MaterialSource for Client {
+    async fn list_documents(&self) -> Result<NodeTree> {
+        let documents = self
+            .all_documents(false)
+            .await
+            .context("Fetching all document metadata from rM Cloud")?;
+        Ok(NodeTree::from_tree(&documents))
+    }
+
+    async fn download_zip(&self, id: Uuid) -> Result<Vec<u8>> {
+        let zip = Client::download_zip(self, id)
+            .await
+            .context("Downloading document zip from rM Cloud")?;
+        Ok(zip.into_inner().into_inner())
+    }
+}
+
+/// The rM cloud client along with what it takes to make the storage
+/// requests it has no API for.
+pub struct RmCloud {
+    client: Client,
+    http: reqwest::Client,
+    cloud: CloudEndpoints,
+    user_token: String,
+}
+
+/// The part of a document storage entry needed to change it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StorageEntry {
+    #[serde(rename = "ID")]
+    id: String,
+    version: u64,
+    #[serde(default)]
+    success: bool,
+    #[serde(default)]
+    message: String,
+}
+
+impl RmCloud {
+    pub fn new(
+        cloud: &CloudEndpoints,
+        http: reqwest::Client,
+        device_token: String,
+        user_token: String,
+    ) -> Self {
+        let state = ClientState {
+            device_token,
+            user_token: user_token.clone(),
+        };
+        let client = Client::new(state, http.clone());
+        Self {
+            client,
+            http,
+            cloud: cloud.clone(),
+            user_token,
+        }
+    }
+
+    /// The current version of a document, which a delete has to name.
+    async fn version(&self, id: Uuid) -> Result<u64> {
+        let mut url = self.cloud.url(DOCS_URL)?;
+        url.query_pairs_mut().append_pair("doc", &id.to_string());
+        let entries: Vec<StorageEntry> = self
+            .http
+            .get(url)
+            .bearer_auth(&self.user_token)
+            .send()
+            .await
+            .context("Requesting document from rM cloud")?
+            .error_for_status()
+            .context("rM cloud returned an error for the document")?
+            .json()
+            .await
+            .context("Parsing document from rM cloud")?;
+        entries
+            .into_iter()
+            .find(|entry| entry.id == id.to_string())
+            .map(|entry| entry.version)
+            .ok_or_else(|| anyhow!("Document {} is not in rM cloud", id))
+    }
+}
+
+#[async_trait]
+impl MaterialSource for RmCloud {
+    async fn list_documents(&self) -> Result<NodeTree> {
+        self.client.list_documents().await
+    }
+
+    async fn download_zip(&self, id: Uuid) -> Result<Vec<u8>> {
+        MaterialSource::download_zip(&self.client, id).await
+    }
+}
+
+#[async_trait]
+impl CloudBackend for RmCloud {
+    async fn create_folder(&self, id: Uuid, name: String, parent: Option<Uuid>) -> Result<Uuid> {
+        let folder_id = Client::create_folder(&self.client, id, name, to_parent(parent))
+            .await
+            .context("Creating folder on rM Cloud")?;
+        Ok(folder_id)
+    }
+
+    async fn upload_notebook(
+        &self,
+        id: Uuid,
+        name: String,
+        parent: Option<Uuid>,
+        zip: Vec<u8>,
+    ) -> Result<Uuid> {
+        let mut zip =
+            zip::ZipArchive::new(std::io::Cursor::new(zip)).context("Reading ZipArchive")?;
+        Client::upload_notebook(&self.client, id, name, to_parent(parent), &mut zip)
+            .await
+            .context("Uploading notebook to rM Cloud")?;
+        Ok(id)
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<()> {
+        let version = self.version(id).await?;
+        let results: Vec<StorageEntry> = self
+            .http
+            .put(self.cloud.url(DELETE_URL)?)
+            .bearer_auth(&self.user_token)
+            .json(&serde_json::json!([{ "ID": id.to_string(), "Version": version }]))
+            .send()
+            .await
+            .context("Deleting document on rM Cloud")?
+            .error_for_status()
+            .context("rM cloud returned an error deleting the document")?
+            .json()
+            .await
+            .context("Parsing rM cloud delete response")?;
+        match results.into_iter().find(|entry| entry.id == id.to_string()) {
+            Some(entry) if entry.success => Ok(()),
+            Some(entry) => Err(anyhow!("rM cloud didn't delete {}: {}", id, entry.message)),
+            None => Err(anyhow!("rM cloud didn't report deleting {}", id)),
+        }
+    }
+}
+
+#[async_trait]
+impl MaterialSource for UsbClient {
+    async fn list_documents(&self) -> Result<NodeTree> {
+        self.all_documents().await
+    }
+
+    async fn download_zip(&self, id: Uuid) -> Result<Vec<u8>> {
+        UsbClient::download_zip(self, id).await
+    }
+}
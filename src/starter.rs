@@ -0,0 +1,191 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use include_dir::{include_dir, Dir, DirEntry};
+use remarkable_cloud_api::Uuid;
+
+use crate::export::create_empty_dir;
+use crate::fetch::write_zip;
+use crate::manifest::{DocumentTree, Manifest, Posts};
+use crate::source::MaterialSource;
+
+/// Starter sites built into the binary, one directory per starter laid out
+/// like a site folder: `Home.zip`, `Logo.zip` and a `Posts/` tree.
+static STARTERS: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/starters");
+
+/// A starter tree, either one built into the binary or a local directory.
+pub enum Starter {
+    Embedded(&'static Dir<'static>),
+    Local(PathBuf),
+}
+
+impl Starter {
+    pub const DEFAULT: &'static str = "blog";
+
+    /// Looks up `name_or_dir` as the name of a built-in starter, unless it
+    /// contains a path separator, then as a local directory.
+    pub fn find(name_or_dir: &str) -> Result<Self> {
+        let path = Path::new(name_or_dir);
+        let builtin = Some(name_or_dir)
+            .filter(|name| !name.contains(std::path::is_separator))
+            .and_then(|name| STARTERS.get_dir(name));
+        if let Some(dir) = builtin {
+            return Ok(Self::Embedded(dir));
+        }
+        if path.is_dir() {
+            return Ok(Self::Local(path.to_path_buf()));
+        }
+        Err(anyhow!(
+            "No built-in starter or starter directory named {:?}, built-in starters are: {}",
+            name_or_dir,
+            Self::names().join(", ")
+        ))
+    }
+
+    /// The names of the built-in starters.
+    pub fn names() -> Vec<&'static str> {
+        let mut names: Vec<&str> = STARTERS
+            .dirs()
+            .filter_map(|dir| dir.path().file_name()?.to_str())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The files of a built-in starter relative to its root, sorted.
+    pub fn files(name: &str) -> Result<Vec<PathBuf>> {
+        let dir = STARTERS
+            .get_dir(name)
+            .ok_or_else(|| anyhow!("No built-in starter named {:?}", name))?;
+        let mut files = Vec::new();
+        collect_files(dir, dir.path(), &mut files);
+        files.sort();
+        Ok(files)
+    }
+
+    /// Writes the starter's files under `out_dir`.
+    pub fn export(&self, out_dir: &Path) -> Result<()> {
+        match self {
+            Self::Embedded(dir) => extract(dir, dir.path(), out_dir),
+            Self::Local(path) => Err(anyhow!("{:?} is already on disk", path)),
+        }
+    }
+
+    /// Downloads the site in `folder`, a `/` separated path of folder names
+    /// from the top level, and writes it to `out_dir` as a starter tree.
+    /// `out_dir` must be empty or missing.
+    pub async fn package(source: &impl MaterialSource, folder: &str, out_dir: &Path) -> Result<()> {
+        let docs = source
+            .list_documents()
+            .await
+            .context("Fetching all document metadata")?;
+
+        let mut folder_id = None;
+        for name in folder.split('/').filter(|name| !name.is_empty()) {
+            let node = docs
+                .child_nodes(folder_id)
+                .into_iter()
+                .find(|n| n.visible_name == name && n.doc_type == "CollectionType")
+                .ok_or_else(|| anyhow!("No folder {:?} in {:?}", name, folder))?;
+            folder_id = Some(node.id);
+        }
+        let folder_id = folder_id.ok_or_else(|| anyhow!("Empty folder path"))?;
+
+        let manifest = Manifest::build(folder_id.to_string(), &docs)
+            .with_context(|| format!("Reading site in {:?}", folder))?;
+
+        create_empty_dir(out_dir)?;
+        let posts_dir = out_dir.join("Posts");
+        std::fs::create_dir_all(&posts_dir).context("Creating starter directory")?;
+
+        download(source, manifest.home.id, &out_dir.join("Home.zip")).await?;
+        download(source, manifest.logo.id, &out_dir.join("Logo.zip")).await?;
+        package_posts(source, &manifest.posts, &posts_dir).await
+    }
+
+    /// Runs `f` with the starter as a directory on disk, extracting built-in
+    /// starters to a temporary directory for the duration.
+    pub async fn with_dir<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(PathBuf) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        match self {
+            Self::Local(path) => f(path.clone()).await,
+            Self::Embedded(_) => {
+                let temp_dir =
+                    std::env::temp_dir().join(format!("marker-starter-{}", Uuid::new_v4()));
+                self.export(&temp_dir)
+                    .context("Extracting built-in starter")?;
+                let result = f(temp_dir.clone()).await;
+                let _ = std::fs::remove_dir_all(&temp_dir);
+                result
+            }
+        }
+    }
+}
+
+fn collect_files(dir: &Dir, root: &Path, files: &mut Vec<PathBuf>) {
+    for entry in dir.entries() {
+        match entry {
+            DirEntry::Dir(sub_dir) => collect_files(sub_dir, root, files),
+            DirEntry::File(file) => {
+                files.push(file.path().strip_prefix(root).unwrap_or(file.path()).into())
+            }
+        }
+    }
+}
+
+fn extract(dir: &Dir, root: &Path, out_dir: &Path) -> Result<()> {
+    for entry in dir.entries() {
+        match entry {
+            DirEntry::Dir(sub_dir) => extract(sub_dir, root, out_dir)?,
+            DirEntry::File(file) => {
+                let relative = file.path().strip_prefix(root).unwrap_or(file.path());
+                let path = out_dir.join(relative);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("Creating {:?}", parent))?;
+                }
+                std::fs::write(&path, file.contents())
+                    .with_context(|| format!("Writing {:?}", path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[async_recursion::async_recursion]
+async fn package_posts<S>(source: &S, posts: &Posts, dir: &Path) -> Result<()>
+where
+    S: MaterialSource,
+{
+    for (name, doc) in posts.documents.iter() {
+        download(
+            source,
+            doc.id,
+            &dir.join(format!("{}.zip", file_name(name))),
+        )
+        .await?;
+    }
+    for (name, folder) in posts.folders.iter() {
+        let sub_dir = dir.join(file_name(name));
+        std::fs::create_dir_all(&sub_dir).with_context(|| format!("Creating {:?}", sub_dir))?;
+        package_posts(source, folder, &sub_dir).await?;
+    }
+    Ok(())
+}
+
+async fn download(source: &impl MaterialSource, id: Uuid, path: &Path) -> Result<()> {
+    println!("Packaging {:?}", path);
+    let bytes = source
+        .download_zip(id)
+        .await
+        .context("Downloading document zip")?;
+    write_zip(path, &bytes).with_context(|| format!("Writing {:?}", path))
+}
+
+/// rM names may contain `/`, which can't appear in a file name.
+fn file_name(name: &str) -> String {
+    name.replace('/', "-")
+}
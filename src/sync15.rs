@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use remarkable_cloud_api::{reqwest, Uuid};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::cloud::CloudEndpoints;
+use crate::manifest::NodeTree;
+use crate::source::MaterialSource;
+use crate::xochitl::{self, Metadata};
+
+const SYNC_URL: &str = "https://internal.cloud.remarkable.com/sync/v3";
+
+#[derive(Debug, Deserialize)]
+struct Root {
+    hash: String,
+}
+
+/// A line of an index file: `<hash>:<type>:<id>:<subfiles>:<size>`.
+#[derive(Debug, Clone)]
+struct IndexEntry {
+    hash: String,
+    id: String,
+}
+
+/// Fetches material with the hash-tree based sync protocol used by current rM
+/// cloud accounts.
+///
+/// The root hash points at an index of documents, each document hash points
+/// at an index of its files and every file is a blob addressed by its hash.
+/// Blobs are cached on disk by hash, so only what changed since the last fetch
+/// is downloaded. Listing the documents drops blobs nothing refers to anymore.
+pub struct SyncClient {
+    http: reqwest::Client,
+    base: url::Url,
+    user_token: String,
+    blob_dir: PathBuf,
+    documents: Mutex<BTreeMap<Uuid, Vec<IndexEntry>>>,
+}
+
+impl SyncClient {
+    /// A client for the sync endpoints of `cloud`, rewritten onto its
+    /// `cloud_url` for a self-hosted cloud.
+    pub fn new(
+        http: reqwest::Client,
+        cloud: &CloudEndpoints,
+        user_token: String,
+        blob_dir: PathBuf,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(&blob_dir).context("Creating blob cache directory")?;
+        Ok(Self {
+            http,
+            base: cloud.url(SYNC_URL)?,
+            user_token,
+            blob_dir,
+            documents: Default::default(),
+        })
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let bytes = self
+            .http
+            .get(format!(
+                "{}/{}",
+                self.base.as_str().trim_end_matches('/'),
+                path
+            ))
+            .bearer_auth(&self.user_token)
+            .send()
+            .await
+            .with_context(|| format!("Requesting {}", path))?
+            .error_for_status()
+            .with_context(|| format!("rM cloud returned an error for {}", path))?
+            .bytes()
+            .await
+            .with_context(|| format!("Reading {}", path))?;
+        Ok(bytes.to_vec())
+    }
+
+    async fn root_hash(&self) -> Result<String> {
+        let root: Root =
+            serde_json::from_slice(&self.get("root").await?).context("Parsing sync root")?;
+        Ok(root.hash)
+    }
+
+    async fn blob(&self, hash: &str) -> Result<Vec<u8>> {
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("Invalid blob hash {:?}", hash));
+        }
+        let cached_path = self.blob_dir.join(hash);
+        if let Ok(bytes) = std::fs::read(&cached_path) {
+            if sha256_hex(&bytes).eq_ignore_ascii_case(hash) {
+                return Ok(bytes);
+            }
+            println!("Fetching blob {} again, the cached copy is corrupt", hash);
+        }
+
+        let bytes = self.get(&format!("files/{}", hash)).await?;
+        if !sha256_hex(&bytes).eq_ignore_ascii_case(hash) {
+            return Err(anyhow!(
+                "Blob {} from the rM cloud doesn't match its hash",
+                hash
+            ));
+        }
+
+        // Unique per fetch, the same blob can be fetched by several tasks at once
+        let temp_path = self
+            .blob_dir
+            .join(format!("{}.{}.tmp", hash, Uuid::new_v4()));
+        let mut file = std::fs::File::create(&temp_path).context("Creating blob cache file")?;
+        file.write_all(&bytes).context("Writing blob cache file")?;
+        std::fs::rename(&temp_path, &cached_path).context("Renaming blob cache file")?;
+        Ok(bytes)
+    }
+
+    async fn index(&self, hash: &str) -> Result<Vec<IndexEntry>> {
+        let bytes = self.blob(hash).await?;
+        let text = String::from_utf8(bytes).context("Index file is not utf8")?;
+        parse_index(&text).with_context(|| format!("Parsing index {}", hash))
+    }
+
+    /// The metadata of a document from its file index, `None` with a warning
+    /// if it has no readable `.metadata` file.
+    async fn metadata(&self, id: Uuid, files: &[IndexEntry]) -> Result<Option<Metadata>> {
+        let entry = match files.iter().find(|f| f.id.ends_with(".metadata")) {
+            Some(entry) => entry,
+            None => {
+                println!("Skipping document {}, it has no .metadata file", id);
+                return Ok(None);
+            }
+        };
+        let bytes = self.blob(&entry.hash).await?;
+        match serde_json::from_slice(&bytes) {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e) => {
+                println!(
+                    "Skipping document {}, its .metadata is malformed: {}",
+                    id, e
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes cached blobs not in `keep`, and temp files left by
+    /// interrupted downloads.
+    fn gc_blobs(&self, keep: &BTreeSet<String>) -> Result<()> {
+        for entry in std::fs::read_dir(&self.blob_dir).context("Listing blob cache")? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if path.is_file() && !keep.contains(name.as_ref()) {
+                println!("Removing stale blob {:?}", path);
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Removing stale blob {:?}", path))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_index(text: &str) -> Result<Vec<IndexEntry>> {
+    let mut lines = text.lines();
+    match lines.next() {
+        Some("3") | Some("4") => (),
+        version => return Err(anyhow!("Unsupported index schema {:?}", version)),
+    }
+
+    let mut entries = Vec::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            // schema 4 starts with a summary line for the index itself
+            [_, ".", ..] => continue,
+            [hash, _kind, id, _subfiles, _size] => entries.push(IndexEntry {
+                hash: hash.to_string(),
+                id: id.to_string(),
+            }),
+            _ => return Err(anyhow!("Malformed index line {:?}", line)),
+        }
+    }
+    Ok(entries)
+}
+
+#[async_trait]
+impl MaterialSource for SyncClient {
+    async fn list_documents(&self) -> Result<NodeTree> {
+        let root_hash = self.root_hash().await.context("Fetching sync root")?;
+        let root_index = self
+            .index(&root_hash)
+            .await
+            .context("Fetching root index")?;
+
+        let mut metadata = BTreeMap::new();
+        let mut documents = BTreeMap::new();
+        let mut referenced = BTreeSet::from([root_hash]);
+        for doc in root_index {
+            let id = Uuid::parse_str(&doc.id).context("Parsing document ID in root index")?;
+            let files = self
+                .index(&doc.hash)
+                .await
+                .with_context(|| format!("Fetching index of document {}", id))?;
+            referenced.insert(doc.hash);
+            referenced.extend(files.iter().map(|f| f.hash.clone()));
+
+            if let Some(meta) = self
+                .metadata(id, &files)
+                .await
+                .with_context(|| format!("Fetching metadata of document {}", id))?
+            {
+                metadata.insert(id, meta);
+                documents.insert(id, files);
+            }
+        }
+        self.gc_blobs(&referenced).context("Removing stale blobs")?;
+
+        *self
+            .documents
+            .lock()
+            .map_err(|_| anyhow!("Document index poisoned"))? = documents;
+
+        Ok(xochitl::tree_from_metadata(&metadata))
+    }
+
+    async fn download_zip(&self, id: Uuid) -> Result<Vec<u8>> {
+        let files = self
+            .documents
+            .lock()
+            .map_err(|_| anyhow!("Document index poisoned"))?
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Document {} was not listed", id))?;
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for file in files {
+            let bytes = self
+                .blob(&file.hash)
+                .await
+                .with_context(|| format!("Downloading {}", file.id))?;
+            zip.start_file(file.id, zip::write::FileOptions::default())
+                .context("Adding file to document zip")?;
+            zip.write_all(&bytes)
+                .context("Writing file into document zip")?;
+        }
+        let cursor = zip.finish().context("Finishing document zip")?;
+        Ok(cursor.into_inner())
+    }
+}
@@ -13,13 +13,13 @@ impl Theme {
     pub fn load(theme: &Path) -> Result<Self> {
         let mut handlebars = Handlebars::new();
         handlebars
-            .register_template_file("index", &theme.join("index.html"))
+            .register_template_file("index", theme.join("index.html"))
             .context("Registering index template")?;
         handlebars
-            .register_template_file("document", &theme.join("document.html"))
+            .register_template_file("document", theme.join("document.html"))
             .context("Registering document template")?;
         handlebars
-            .register_template_file("folder", &theme.join("folder.html"))
+            .register_template_file("folder", theme.join("folder.html"))
             .context("Registering folder template")?;
         let css = theme.join("style.css");
         if !css.exists() {
@@ -30,7 +30,7 @@ impl Theme {
 
     pub fn render_index(&self, params: &handlebars::JsonValue, gen_root: &Path) -> Result<()> {
         let f_out =
-            std::fs::File::create(&gen_root.join("index.html")).context("Creating index.html")?;
+            std::fs::File::create(gen_root.join("index.html")).context("Creating index.html")?;
         self.handlebars
             .render_to_write("index", params, f_out)
             .context("Rendering index.html")?;
@@ -38,7 +38,7 @@ impl Theme {
     }
 
     pub fn render_document(&self, params: &handlebars::JsonValue, out: &Path) -> Result<()> {
-        let f_out = std::fs::File::create(&out).context("Creating document file for rendering")?;
+        let f_out = std::fs::File::create(out).context("Creating document file for rendering")?;
         self.handlebars
             .render_to_write("document", params, f_out)
             .context("Rendering document template")?;
@@ -46,7 +46,7 @@ impl Theme {
     }
 
     pub fn render_folder(&self, params: &handlebars::JsonValue, out: &Path) -> Result<()> {
-        let f_out = std::fs::File::create(&out).context("Creating folder file for rendering")?;
+        let f_out = std::fs::File::create(out).context("Creating folder file for rendering")?;
         self.handlebars
             .render_to_write("folder", params, f_out)
             .context("Cendering folder template")?;
@@ -54,7 +54,7 @@ impl Theme {
     }
 
     pub fn render_css(&self, gen_root: &Path) -> Result<()> {
-        std::fs::copy(&self.css, &gen_root.join("style.css"))
+        std::fs::copy(&self.css, gen_root.join("style.css"))
             .context("Copying theme css into generated site")?;
         Ok(())
     }
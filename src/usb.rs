@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use async_recursion::async_recursion;
+use remarkable_cloud_api::{reqwest, Uuid};
+use serde::Deserialize;
+
+use crate::manifest::{Node, NodeTree};
+
+/// An entry as listed by the USB web interface under `/documents/`.
+#[derive(Debug, Deserialize)]
+struct UsbDocument {
+    #[serde(rename = "ID")]
+    id: Uuid,
+    #[serde(rename = "VissibleName")]
+    visible_name: String,
+    #[serde(rename = "Type")]
+    doc_type: String,
+    #[serde(rename = "ModifiedClient")]
+    modified_client: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Talks to the web interface the tablet serves while plugged in over USB.
+pub struct UsbClient {
+    http: reqwest::Client,
+    base: url::Url,
+}
+
+impl UsbClient {
+    /// The tablet's address on the network it sets up over USB.
+    pub const DEFAULT_HOST: &'static str = "10.11.99.1";
+
+    pub fn new(host: &str) -> Result<Self> {
+        let base = url::Url::parse(&format!("http://{}/", host))
+            .with_context(|| format!("Parsing USB host {:?}", host))?;
+        let http = reqwest::Client::builder()
+            .user_agent("marker-network-site-generator-cli")
+            .build()
+            .context("Building reqwest client")?;
+        Ok(Self { http, base })
+    }
+
+    /// Walks every folder on the tablet and collects the full hierarchy.
+    pub async fn all_documents(&self) -> Result<NodeTree> {
+        let mut tree = NodeTree::default();
+        self.collect_folder(None, &mut tree).await?;
+        Ok(tree)
+    }
+
+    #[async_recursion]
+    async fn collect_folder(&self, folder: Option<Uuid>, tree: &mut NodeTree) -> Result<()> {
+        let path = match folder {
+            Some(id) => format!("documents/{}", id),
+            None => "documents/".to_string(),
+        };
+        let bytes = self
+            .get(&path)
+            .await
+            .with_context(|| format!("Listing folder {:?} over USB", folder))?;
+        let docs: Vec<UsbDocument> =
+            serde_json::from_slice(&bytes).context("Parsing USB document listing")?;
+
+        for doc in docs {
+            let is_folder = doc.doc_type == "CollectionType";
+            tree.insert(
+                folder,
+                Node {
+                    id: doc.id,
+                    visible_name: doc.visible_name,
+                    doc_type: doc.doc_type,
+                    // Without a timestamp there's no telling whether the
+                    // document changed, so it counts as changed just now and
+                    // is fetched again on every run
+                    modified_client: doc.modified_client.unwrap_or_else(chrono::Utc::now),
+                },
+            );
+            if is_folder {
+                self.collect_folder(Some(doc.id), tree).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Downloads a document as an `.rmdoc`, a zip with the same layout the
+    /// rM cloud serves.
+    pub async fn download_zip(&self, id: Uuid) -> Result<Vec<u8>> {
+        self.get(&format!("download/{}/rmdoc", id))
+            .await
+            .with_context(|| format!("Downloading {} over USB", id))
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let url = self.base.join(path).context("Building USB endpoint url")?;
+        let bytes = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .context("Sending request to tablet")?
+            .error_for_status()
+            .context("Tablet returned an error")?
+            .bytes()
+            .await
+            .context("Reading response from tablet")?;
+        Ok(bytes.to_vec())
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use remarkable_cloud_api::Uuid;
+
+use crate::generator::Generator;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const THEME_FILES: &[&str] = &["index.html", "document.html", "folder.html", "style.css"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        Some(Self {
+            modified: meta.modified().ok()?,
+            len: meta.len(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub theme: bool,
+    pub manifest: bool,
+    pub zips: BTreeSet<Uuid>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        !self.theme && !self.manifest && self.zips.is_empty()
+    }
+}
+
+/// Polls the material and theme directories for modifications.
+pub struct Watcher {
+    material_path: PathBuf,
+    theme_dir: PathBuf,
+    manifest: Option<Stamp>,
+    zips: BTreeMap<Uuid, Stamp>,
+    theme: BTreeMap<&'static str, Option<Stamp>>,
+}
+
+impl Watcher {
+    pub fn new(material_path: &Path, theme_dir: &Path) -> Result<Self> {
+        let mut watcher = Self {
+            material_path: material_path.to_path_buf(),
+            theme_dir: theme_dir.to_path_buf(),
+            manifest: None,
+            zips: Default::default(),
+            theme: Default::default(),
+        };
+        watcher
+            .poll()
+            .context("Taking initial snapshot of watched files")?;
+        Ok(watcher)
+    }
+
+    pub fn poll(&mut self) -> Result<Changes> {
+        let mut changes = Changes::default();
+
+        let manifest = Stamp::of(&self.material_path.join("manifest.json"));
+        changes.manifest = manifest != self.manifest;
+        self.manifest = manifest;
+
+        let zips = self.scan_zips().context("Scanning material zips")?;
+        changes.zips.extend(
+            zips.iter()
+                .filter(|(id, stamp)| self.zips.get(*id) != Some(*stamp))
+                .map(|(id, _)| *id),
+        );
+        self.zips = zips;
+
+        for file in THEME_FILES {
+            let stamp = Stamp::of(&self.theme_dir.join(file));
+            if self.theme.insert(*file, stamp) != Some(stamp) {
+                changes.theme = true;
+            }
+        }
+
+        Ok(changes)
+    }
+
+    fn scan_zips(&self) -> Result<BTreeMap<Uuid, Stamp>> {
+        let zip_dir = self.material_path.join("zip");
+        let mut zips = BTreeMap::new();
+        if !zip_dir.exists() {
+            return Ok(zips);
+        }
+        for entry in std::fs::read_dir(&zip_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(std::ffi::OsStr::to_str) != Some("zip") {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .and_then(|stem| Uuid::parse_str(stem).ok());
+            if let (Some(id), Some(stamp)) = (id, Stamp::of(&path)) {
+                zips.insert(id, stamp);
+            }
+        }
+        Ok(zips)
+    }
+}
+
+/// Regenerates the site whenever the material or theme changes, calling
+/// `on_rebuild` after every successful rebuild. Never returns on success.
+pub fn watch(mut generator: Generator, mut on_rebuild: impl FnMut()) -> Result<()> {
+    let mut watcher = Watcher::new(generator.material_path(), &generator.theme_dir())?;
+    println!("Watching for changes..");
+
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let changes = watcher.poll().context("Polling for changes")?;
+        if changes.is_empty() {
+            continue;
+        }
+        println!("Detected changes: {:?}", changes);
+
+        if let Err(e) = rebuild(&mut generator, &changes) {
+            eprintln!("Failed to rebuild site: {:?}", e);
+            continue;
+        }
+        on_rebuild();
+    }
+}
+
+fn rebuild(generator: &mut Generator, changes: &Changes) -> Result<()> {
+    if changes.theme {
+        generator.reload_theme()?;
+    }
+    if changes.manifest || !changes.zips.is_empty() {
+        generator
+            .reload_material(&changes.zips)
+            .context("Reloading material")?;
+    }
+    generator.gen_index().context("Regenerating site")?;
+    generator.prune(false).context("Pruning orphaned output")?;
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use remarkable_cloud_api::Uuid;
+use serde::Deserialize;
+
+use crate::fetch;
+use crate::manifest::{self, Manifest, Node, NodeTree};
+
+/// The subset of a `<uuid>.metadata` file we care about.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Metadata {
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    last_modified: serde_json::Value,
+    #[serde(default)]
+    parent: String,
+    #[serde(rename = "type")]
+    doc_type: String,
+    visible_name: String,
+}
+
+/// A copy of the tablet's `~/.local/share/remarkable/xochitl` directory.
+pub struct Xochitl {
+    root: PathBuf,
+    nodes: NodeTree,
+}
+
+impl Xochitl {
+    pub fn load(root: &Path) -> Result<Self> {
+        let mut metadata = BTreeMap::new();
+        for entry in std::fs::read_dir(root).context("Listing xochitl directory")? {
+            let path = entry?.path();
+            if path.extension().and_then(std::ffi::OsStr::to_str) != Some("metadata") {
+                continue;
+            }
+            let id = match path
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .and_then(|stem| Uuid::parse_str(stem).ok())
+            {
+                Some(id) => id,
+                None => {
+                    println!("Skipping {:?}, it isn't named by a document UUID", path);
+                    continue;
+                }
+            };
+            let file = std::fs::File::open(&path).context("Opening metadata file")?;
+            match serde_json::from_reader::<_, Metadata>(file) {
+                Ok(mut meta) => {
+                    if manifest::parse_last_modified(&meta.last_modified).is_none() {
+                        if let Some(modified) = files_modified(root, id) {
+                            meta.last_modified = modified;
+                        }
+                    }
+                    metadata.insert(id, meta);
+                }
+                Err(e) => println!(
+                    "Skipping document {}, its .metadata is malformed: {}",
+                    id, e
+                ),
+            }
+        }
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            nodes: tree_from_metadata(&metadata),
+        })
+    }
+
+    /// Builds the site manifest from the dump and writes the material
+    /// (`manifest.json` + `zip/<uuid>.zip`) under `material_root`.
+    pub fn import(&self, site_root: String, material_root: &Path) -> Result<Manifest> {
+        let archives_dir = material_root.join("zip");
+        std::fs::create_dir_all(&archives_dir).context("Creating zip archives directory")?;
+
+        let existing_docs: BTreeMap<Uuid, manifest::DocumentMeta> =
+            if let Ok(existing_manifest) = Manifest::load(material_root) {
+                existing_manifest
+                    .docs()
+                    .into_iter()
+                    .map(|d| (d.id, d.clone()))
+                    .collect()
+            } else {
+                Default::default()
+            };
+
+        let manifest =
+            Manifest::build(site_root, &self.nodes).context("Building Manifest from xochitl")?;
+
+        for doc in manifest.docs() {
+            let zip_path = archives_dir.join(format!("{}.zip", doc.id));
+            if let Some(existing_doc) = existing_docs.get(&doc.id) {
+                if existing_doc.modified_client >= doc.modified_client && zip_path.exists() {
+                    println!("Nothing new from {}", doc.id);
+                    continue;
+                }
+            }
+            println!("Packaging {}", doc.id);
+            self.write_zip(doc.id, &zip_path)
+                .with_context(|| format!("Packaging document {}", doc.id))?;
+        }
+
+        manifest
+            .save(material_root)
+            .context("Saving the generated Manifest")?;
+
+        fetch::gc_zips(&archives_dir, &manifest).context("Removing stale zips")?;
+
+        Ok(manifest)
+    }
+
+    /// Packs a document's files into a zip laid out like the ones the rM
+    /// cloud serves: `<uuid>.content`, `<uuid>.pagedata` and `<uuid>/<page>.rm`.
+    fn write_zip(&self, id: Uuid, zip_path: &Path) -> Result<()> {
+        let mut entries: Vec<(String, PathBuf)> = Vec::new();
+        for ext in ["content", "pagedata", "pdf", "epub"] {
+            let path = self.root.join(format!("{}.{}", id, ext));
+            if path.exists() {
+                entries.push((format!("{}.{}", id, ext), path));
+            }
+        }
+
+        let pages_dir = self.root.join(id.to_string());
+        if pages_dir.is_dir() {
+            for entry in std::fs::read_dir(&pages_dir).context("Listing document pages")? {
+                let path = entry?.path();
+                if !path.is_file() {
+                    continue;
+                }
+                let file_name = path
+                    .file_name()
+                    .and_then(std::ffi::OsStr::to_str)
+                    .ok_or_else(|| anyhow!("Invalid page file name: {:?}", path))?;
+                entries.push((format!("{}/{}", id, file_name), path.clone()));
+            }
+        }
+
+        let temp_path = zip_path.with_extension("zip.tmp");
+        let zip_file = std::fs::File::create(&temp_path).context("Creating document zip")?;
+        let mut zip = zip::ZipWriter::new(zip_file);
+        for (name, path) in entries {
+            zip.start_file(name, zip::write::FileOptions::default())
+                .context("Adding file to document zip")?;
+            let mut file = std::fs::File::open(&path).context("Opening document file")?;
+            std::io::copy(&mut file, &mut zip).context("Writing file into document zip")?;
+        }
+        zip.finish()
+            .context("Finishing document zip")?
+            .sync_all()
+            .context("Syncing document zip")?;
+
+        std::fs::rename(temp_path, zip_path).context("Renaming tempfile to document zip")?;
+        Ok(())
+    }
+}
+
+/// When a document's `.metadata` or `.content` was last written, in the
+/// format of `lastModified`, for documents whose metadata lacks one.
+fn files_modified(root: &Path, id: Uuid) -> Option<serde_json::Value> {
+    ["metadata", "content"]
+        .iter()
+        .filter_map(|ext| {
+            std::fs::metadata(root.join(format!("{}.{}", id, ext)))
+                .and_then(|m| m.modified())
+                .ok()
+        })
+        .max()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|since| serde_json::Value::String(since.as_millis().to_string()))
+}
+
+/// Builds the visible folder hierarchy out of the `.metadata` files of every
+/// document, the same layout is used on the tablet and in the sync protocol.
+pub(crate) fn tree_from_metadata(metadata: &BTreeMap<Uuid, Metadata>) -> NodeTree {
+    // Anything that is deleted or lives in the trash (directly or through
+    // one of its parents) is not part of the visible hierarchy.
+    let is_visible = |mut id: Uuid| -> bool {
+        for _ in 0..metadata.len() {
+            let meta = match metadata.get(&id) {
+                Some(meta) => meta,
+                None => return false,
+            };
+            if meta.deleted || meta.parent == "trash" {
+                return false;
+            }
+            if meta.parent.is_empty() {
+                return true;
+            }
+            id = match Uuid::parse_str(&meta.parent) {
+                Ok(parent) => parent,
+                Err(_) => return false,
+            };
+        }
+        false // parent cycle
+    };
+
+    let mut nodes = NodeTree::default();
+    for (id, meta) in metadata.iter().filter(|(id, _)| is_visible(**id)) {
+        nodes.insert(
+            Uuid::parse_str(&meta.parent).ok(),
+            Node {
+                id: *id,
+                visible_name: meta.visible_name.clone(),
+                doc_type: meta.doc_type.clone(),
+                // A stable fallback keeps the document from being
+                // refetched on every run, dumps fill in their file times
+                // before this
+                modified_client: manifest::parse_last_modified(&meta.last_modified)
+                    .unwrap_or_else(|| std::time::UNIX_EPOCH.into()),
+            },
+        );
+    }
+
+    nodes
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use marker_network_site_generator::{
+    fetch, init, push, sanitize, CloudBackend, CloudEndpoints, Config, DocumentTree, FakeCloud,
+    FetchOptions, Generator, Manifest, MaterialSource,
+};
+use remarkable_cloud_api::Uuid;
+
+use common::TempDir;
+
+fn files_with_extension(dir: &Path, extension: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Listing {:?}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(files_with_extension(&path, extension)?);
+        } else if path.extension().and_then(std::ffi::OsStr::to_str) == Some(extension) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[tokio::test]
+async fn init_fetch_and_gen_the_blog_starter() -> Result<()> {
+    let starter = Path::new(env!("CARGO_MANIFEST_DIR")).join("starters/blog");
+    let temp = TempDir::new();
+    let config_path = temp.0.join("marker-site.json");
+    let material_path = temp.0.join("material");
+    let build_path = temp.0.join("build");
+
+    let cloud = FakeCloud::seeded(&starter, "Marker").await?;
+
+    // The seeded folder looks like an interrupted init, which resumes into
+    // it and only writes the config.
+    init(
+        &cloud,
+        "Marker".to_string(),
+        starter.clone(),
+        CloudEndpoints::default(),
+        config_path.clone(),
+        false,
+    )
+    .await?;
+    // Same-named notebooks are told apart from the listing alone
+    assert_eq!(cloud.downloads(), 0);
+
+    let mut config = Config::load(&config_path)?;
+    assert_eq!(config.title, "Marker");
+    config.cache_dir = Some(temp.0.join("cache"));
+    config.save(&config_path)?;
+
+    fetch(
+        Config::load(&config_path)?,
+        &cloud,
+        &material_path,
+        FetchOptions::default(),
+    )
+    .await?;
+
+    let manifest = Manifest::load(&material_path)?;
+    assert_eq!(manifest.docs().len(), 5);
+    assert_eq!(
+        files_with_extension(&material_path.join("zip"), "zip")?.len(),
+        5
+    );
+
+    let generator = Generator::prepare(
+        Config::load(&config_path)?,
+        material_path,
+        build_path.clone(),
+        PathBuf::from("/"),
+        false,
+    )?;
+    generator.gen_index()?;
+
+    let posts = build_path.join("posts");
+    let folder = sanitize("Folders Work Too");
+    for page in [
+        build_path.join("index.html"),
+        build_path.join("style.css"),
+        posts.join(format!("{}.html", sanitize("Sample Notebook"))),
+        posts.join(format!("{}.html", folder)),
+        posts
+            .join(&folder)
+            .join(format!("{}.html", sanitize("Boxes + Arrows"))),
+        posts
+            .join(&folder)
+            .join(format!("{}.html", sanitize("Pythagorean Theorem"))),
+    ] {
+        assert!(page.is_file(), "{:?} was not generated", page);
+    }
+
+    let index = std::fs::read_to_string(build_path.join("index.html"))?;
+    assert!(index.contains("Sample Notebook"));
+    assert!(!files_with_extension(&build_path.join("svg"), "svg")?.is_empty());
+    // PDF pages stay in the render cache
+    assert!(files_with_extension(&build_path, "json")?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn push_replaces_every_same_named_document() -> Result<()> {
+    let starter = Path::new(env!("CARGO_MANIFEST_DIR")).join("starters/blog");
+    let cloud = FakeCloud::seeded(&starter, "Marker").await?;
+    let docs = cloud.list_documents().await?;
+    let site_root = Manifest::site_root("Marker", &docs)?;
+    let posts = docs
+        .child_nodes(Some(site_root.id))
+        .into_iter()
+        .find(|n| n.visible_name == "Posts")
+        .unwrap();
+
+    let notebook = starter.join("Posts/Sample Notebook.zip");
+    let zip = std::fs::read(&notebook)?;
+    cloud
+        .upload_notebook(
+            Uuid::new_v4(),
+            "Sample Notebook".to_string(),
+            Some(posts.id),
+            zip,
+        )
+        .await?;
+
+    let home = starter.join("Home.zip");
+    assert!(push(&cloud, "Marker", &home, "", true, false)
+        .await
+        .is_err());
+    assert!(push(&cloud, "Marker", &notebook, "Posts", false, false)
+        .await
+        .is_err());
+    push(&cloud, "Marker", &notebook, "Posts", true, false).await?;
+
+    let docs = cloud.list_documents().await?;
+    let named: Vec<_> = docs
+        .child_nodes(Some(posts.id))
+        .into_iter()
+        .filter(|n| n.visible_name == "Sample Notebook")
+        .collect();
+    assert_eq!(named.len(), 1);
+    Ok(())
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use std::path::Path;
+
+use anyhow::Result;
+use marker_network_site_generator::Manifest;
+
+use common::TempDir;
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            std::fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn stages_what_from_directory_reads() -> Result<()> {
+    let starter = Path::new(env!("CARGO_MANIFEST_DIR")).join("starters/blog");
+    let temp = TempDir::new();
+    let material_path = temp.0.join("material");
+
+    let manifest = Manifest::from_directory(&starter)?;
+    let staged = Manifest::stage_directory(&starter, &material_path)?;
+    assert_eq!(staged.docs().len(), manifest.docs().len());
+    for doc in manifest.docs() {
+        assert!(material_path
+            .join("zip")
+            .join(format!("{}.zip", doc.id))
+            .is_file());
+    }
+    Ok(())
+}
+
+#[test]
+fn refuses_two_zips_of_one_document() -> Result<()> {
+    let starter = Path::new(env!("CARGO_MANIFEST_DIR")).join("starters/blog");
+    let temp = TempDir::new();
+    let site = temp.0.join("site");
+    copy_dir(&starter, &site)?;
+    std::fs::copy(site.join("Home.zip"), site.join("Posts/Home again.zip"))?;
+
+    let err = format!("{:#}", Manifest::from_directory(&site).unwrap_err());
+    assert!(err.contains("Home.zip"), "{}", err);
+    assert!(err.contains("Home again.zip"), "{}", err);
+    Ok(())
+}
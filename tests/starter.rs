@@ -0,0 +1,48 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use marker_network_site_generator::{Manifest, Starter};
+
+use common::TempDir;
+
+#[test]
+fn finds_every_built_in_starter_by_name() -> Result<()> {
+    let names = Starter::names();
+    assert!(names.contains(&"blog"));
+    assert!(names.contains(&"notes"));
+
+    for name in ["blog", "notes"] {
+        assert!(matches!(Starter::find(name)?, Starter::Embedded(_)));
+    }
+    assert_ne!(Starter::files("blog")?, Starter::files("notes")?);
+    assert!(Starter::files("notes")?.contains(&PathBuf::from("Posts/Week 1/Limits.zip")));
+
+    // A path is always a local directory, even when it ends in a built-in name
+    let local = Path::new(env!("CARGO_MANIFEST_DIR")).join("starters/notes");
+    assert!(matches!(
+        Starter::find(local.to_str().unwrap())?,
+        Starter::Local(path) if path == local
+    ));
+    assert!(Starter::find("no such starter").is_err());
+    Ok(())
+}
+
+#[test]
+fn exports_the_notes_starter_as_a_site() -> Result<()> {
+    let temp = TempDir::new();
+    let out_dir = temp.0.join("notes");
+    Starter::find("notes")?.export(&out_dir)?;
+
+    let manifest = Manifest::from_directory(&out_dir)?;
+    let posts: Vec<Vec<String>> = manifest.posts.docs().into_keys().collect();
+    assert_eq!(
+        posts,
+        [
+            vec!["Week 1".to_string(), "Limits".to_string()],
+            vec!["Week 2".to_string(), "Derivatives".to_string()],
+        ]
+    );
+    Ok(())
+}
@@ -0,0 +1,275 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Result;
+use marker_network_site_generator::{
+    fetch, CloudEndpoints, Config, DocumentTree, FetchOptions, Manifest, MaterialSource, SyncClient,
+};
+use remarkable_cloud_api::{reqwest, Uuid};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use common::{StubServer, TempDir};
+
+fn hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A recorded sync hash tree: the blobs by hash and the root hash.
+#[derive(Default)]
+struct HashTree {
+    blobs: BTreeMap<String, Vec<u8>>,
+    root_index: String,
+}
+
+impl HashTree {
+    fn blob(&mut self, bytes: Vec<u8>) -> String {
+        let hash = hash(&bytes);
+        self.blobs.insert(hash.clone(), bytes);
+        hash
+    }
+
+    /// Adds a document made of `files`, named relative to its id.
+    fn document(&mut self, id: Uuid, files: Vec<(String, Vec<u8>)>) {
+        let mut index = String::from("3\n");
+        for (name, bytes) in files {
+            let size = bytes.len();
+            let hash = self.blob(bytes);
+            index.push_str(&format!("{}:0:{}{}:0:{}\n", hash, id, name, size));
+        }
+        let hash = self.blob(index.into_bytes());
+        self.root_index
+            .push_str(&format!("{}:80000000:{}:0:0\n", hash, id));
+    }
+
+    fn metadata(name: &str, doc_type: &str, parent: Option<Uuid>) -> (String, Vec<u8>) {
+        let metadata = json!({
+            "deleted": false,
+            "lastModified": "1622171160000",
+            "parent": parent.map(|p| p.to_string()).unwrap_or_default(),
+            "type": doc_type,
+            "visibleName": name,
+        });
+        (
+            ".metadata".to_string(),
+            serde_json::to_vec(&metadata).unwrap(),
+        )
+    }
+
+    fn folder(&mut self, id: Uuid, name: &str, parent: Option<Uuid>) {
+        self.document(id, vec![Self::metadata(name, "CollectionType", parent)]);
+    }
+
+    /// A notebook with the files of a starter zip.
+    fn notebook(&mut self, id: Uuid, name: &str, parent: Uuid, starter_zip: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("starters/blog")
+            .join(starter_zip);
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+        let mut files = vec![Self::metadata(name, "DocumentType", Some(parent))];
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).unwrap();
+            let suffix = file.name()[file.name().find(['.', '/']).unwrap()..].to_string();
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).unwrap();
+            files.push((suffix, bytes));
+        }
+        self.document(id, files);
+    }
+
+    async fn serve(mut self) -> (StubServer, String) {
+        let root_index = format!("3\n{}", self.root_index);
+        let root_hash = self.blob(root_index.into_bytes());
+        let mut responses: BTreeMap<String, Vec<u8>> = self
+            .blobs
+            .into_iter()
+            .map(|(hash, bytes)| (format!("/sync/v3/files/{}", hash), bytes))
+            .collect();
+        responses.insert(
+            "/sync/v3/root".to_string(),
+            serde_json::to_vec(&json!({ "hash": root_hash, "generation": 1 })).unwrap(),
+        );
+        (StubServer::start(responses).await, root_hash)
+    }
+}
+
+struct Site {
+    server: StubServer,
+    root_hash: String,
+    ids: BTreeMap<&'static str, Uuid>,
+}
+
+/// A `Marker` site next to a document without `.metadata` and one whose
+/// `.metadata` is malformed.
+async fn site() -> Site {
+    let ids: BTreeMap<&str, Uuid> = [
+        "Marker",
+        "Home",
+        "Logo",
+        "Posts",
+        "Sample Notebook",
+        "No metadata",
+        "Bad metadata",
+    ]
+    .into_iter()
+    .map(|name| (name, Uuid::new_v4()))
+    .collect();
+
+    let mut tree = HashTree::default();
+    tree.folder(ids["Marker"], "Marker", None);
+    tree.notebook(ids["Home"], "Home", ids["Marker"], "Home.zip");
+    tree.notebook(ids["Logo"], "Logo", ids["Marker"], "Logo.zip");
+    tree.folder(ids["Posts"], "Posts", Some(ids["Marker"]));
+    tree.notebook(
+        ids["Sample Notebook"],
+        "Sample Notebook",
+        ids["Posts"],
+        "Posts/Sample Notebook.zip",
+    );
+    tree.document(
+        ids["No metadata"],
+        vec![(".content".to_string(), b"{}".to_vec())],
+    );
+    tree.document(
+        ids["Bad metadata"],
+        vec![(".metadata".to_string(), b"{\"visibleName\":".to_vec())],
+    );
+
+    let (server, root_hash) = tree.serve().await;
+    Site {
+        server,
+        root_hash,
+        ids,
+    }
+}
+
+fn client(site: &Site, blob_dir: &Path) -> Result<SyncClient> {
+    let cloud = CloudEndpoints {
+        cloud_url: Some(format!("http://{}", site.server.addr)),
+        ..Default::default()
+    };
+    SyncClient::new(
+        reqwest::Client::new(),
+        &cloud,
+        "user-token".to_string(),
+        blob_dir.to_path_buf(),
+    )
+}
+
+fn blob_requests(server: &StubServer) -> usize {
+    server
+        .requests()
+        .iter()
+        .filter(|path| path.starts_with("/sync/v3/files/"))
+        .count()
+}
+
+#[tokio::test]
+async fn lists_documents_skipping_broken_metadata() -> Result<()> {
+    let site = site().await;
+    let temp = TempDir::new();
+    let client = client(&site, &temp.0.join("blobs"))?;
+
+    let tree = client.list_documents().await?;
+    let top: Vec<String> = tree
+        .child_nodes(None)
+        .into_iter()
+        .map(|n| n.visible_name)
+        .collect();
+    assert_eq!(top, ["Marker"]);
+    assert!(tree.node(site.ids["No metadata"]).is_none());
+    assert!(tree.node(site.ids["Bad metadata"]).is_none());
+
+    let site_docs = tree.child_nodes(Some(site.ids["Marker"]));
+    assert_eq!(site_docs.len(), 3);
+    let home = tree.node(site.ids["Home"]).unwrap();
+    assert_eq!(home.modified_client.timestamp_millis(), 1622171160000);
+
+    let zip = client.download_zip(site.ids["Home"]).await?;
+    let zip = zip::ZipArchive::new(std::io::Cursor::new(zip))?;
+    let names: Vec<&str> = zip.file_names().collect();
+    assert!(names.contains(&format!("{}/0.rm", site.ids["Home"]).as_str()));
+    assert!(names.contains(&format!("{}.content", site.ids["Home"]).as_str()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn caches_blobs_and_removes_stale_ones() -> Result<()> {
+    let site = site().await;
+    let temp = TempDir::new();
+    let blob_dir = temp.0.join("blobs");
+    std::fs::create_dir_all(&blob_dir)?;
+    let stale = blob_dir.join(hash(b"an old version"));
+    std::fs::write(&stale, b"an old version")?;
+    let leftover = blob_dir.join(format!("{}.tmp", hash(b"interrupted")));
+    std::fs::write(&leftover, b"interr")?;
+
+    let client = client(&site, &blob_dir)?;
+    client.list_documents().await?;
+    assert!(!stale.exists());
+    assert!(!leftover.exists());
+    assert!(blob_dir.join(&site.root_hash).is_file());
+
+    // Everything listed is cached, listing again only asks for the root
+    let listed = blob_requests(&site.server);
+    client.list_documents().await?;
+    assert_eq!(blob_requests(&site.server), listed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn refuses_blobs_that_do_not_match_their_hash() -> Result<()> {
+    let mut tree = HashTree::default();
+    let (name, metadata) = HashTree::metadata("Marker", "CollectionType", None);
+    let metadata_hash = hash(&metadata);
+    tree.document(Uuid::new_v4(), vec![(name, metadata)]);
+    tree.blobs
+        .insert(metadata_hash.clone(), b"not the metadata".to_vec());
+    let (server, root_hash) = tree.serve().await;
+    let site = Site {
+        server,
+        root_hash,
+        ids: BTreeMap::new(),
+    };
+    let temp = TempDir::new();
+    let blob_dir = temp.0.join("blobs");
+
+    let err = client(&site, &blob_dir)?
+        .list_documents()
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("doesn't match its hash"));
+    assert!(!blob_dir.join(&metadata_hash).exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn fetches_the_site() -> Result<()> {
+    let site = site().await;
+    let temp = TempDir::new();
+    let material_path = temp.0.join("material");
+    let client = client(&site, &material_path.join("blobs"))?;
+    let config = Config::from_reader(
+        &br#"{"site_root": "Marker", "title": "Test", "theme": "marker"}"#[..],
+    )?;
+
+    fetch(config, &client, &material_path, FetchOptions::default()).await?;
+
+    let manifest = Manifest::load(&material_path)?;
+    assert_eq!(manifest.home.id, site.ids["Home"]);
+    assert_eq!(manifest.logo.id, site.ids["Logo"]);
+    assert_eq!(manifest.docs().len(), 3);
+    for doc in manifest.docs() {
+        assert!(material_path
+            .join("zip")
+            .join(format!("{}.zip", doc.id))
+            .is_file());
+    }
+    Ok(())
+}
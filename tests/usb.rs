@@ -0,0 +1,154 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use marker_network_site_generator::{
+    fetch, Config, DocumentTree, FetchOptions, Manifest, MaterialSource, UsbClient,
+};
+use remarkable_cloud_api::Uuid;
+use serde_json::json;
+
+use common::{StubServer, TempDir};
+
+fn starter_zip(name: &str) -> Vec<u8> {
+    std::fs::read(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("starters/blog")
+            .join(name),
+    )
+    .unwrap()
+}
+
+fn entry(id: Uuid, name: &str, doc_type: &str, modified: Option<&str>) -> serde_json::Value {
+    let mut entry = json!({
+        "ID": id.to_string(),
+        "VissibleName": name,
+        "Type": doc_type,
+    });
+    if let Some(modified) = modified {
+        entry["ModifiedClient"] = json!(modified);
+    }
+    entry
+}
+
+/// A tablet with a `Marker` site holding a home page, a logo and one post,
+/// where only the folders and the logo have a modification time.
+async fn tablet() -> (StubServer, BTreeMap<&'static str, Uuid>) {
+    let ids: BTreeMap<&str, Uuid> = ["Marker", "Home", "Logo", "Posts", "Sample Notebook"]
+        .into_iter()
+        .map(|name| (name, Uuid::new_v4()))
+        .collect();
+    let modified = Some("2021-05-28T03:06:00Z");
+
+    let listings = [
+        (
+            "/documents/".to_string(),
+            json!([
+                entry(ids["Marker"], "Marker", "CollectionType", modified),
+                entry(Uuid::new_v4(), "Elsewhere", "DocumentType", None),
+            ]),
+        ),
+        (
+            format!("/documents/{}", ids["Marker"]),
+            json!([
+                entry(ids["Home"], "Home", "DocumentType", None),
+                entry(ids["Logo"], "Logo", "DocumentType", modified),
+                entry(ids["Posts"], "Posts", "CollectionType", modified),
+            ]),
+        ),
+        (
+            format!("/documents/{}", ids["Posts"]),
+            json!([entry(
+                ids["Sample Notebook"],
+                "Sample Notebook",
+                "DocumentType",
+                None
+            )]),
+        ),
+    ];
+    let mut responses: BTreeMap<String, Vec<u8>> = listings
+        .into_iter()
+        .map(|(path, listing)| (path, serde_json::to_vec(&listing).unwrap()))
+        .collect();
+    for (name, file) in [
+        ("Home", "Home.zip"),
+        ("Logo", "Logo.zip"),
+        ("Sample Notebook", "Posts/Sample Notebook.zip"),
+    ] {
+        responses.insert(format!("/download/{}/rmdoc", ids[name]), starter_zip(file));
+    }
+
+    (StubServer::start(responses).await, ids)
+}
+
+#[tokio::test]
+async fn lists_and_downloads_documents() -> Result<()> {
+    let (server, ids) = tablet().await;
+    let usb = UsbClient::new(&server.addr.to_string())?;
+
+    let listed_at = chrono::Utc::now();
+    let tree = usb.list_documents().await?;
+    let top: Vec<String> = tree
+        .child_nodes(None)
+        .into_iter()
+        .map(|n| n.visible_name)
+        .collect();
+    assert_eq!(top.len(), 2);
+    assert!(top.contains(&"Marker".to_string()));
+
+    let posts = tree.child_nodes(Some(ids["Posts"]));
+    assert_eq!(posts.len(), 1);
+    assert_eq!(posts[0].visible_name, "Sample Notebook");
+
+    // A missing modification time counts as modified when listed
+    let home = tree.node(ids["Home"]).unwrap();
+    assert!(home.modified_client >= listed_at);
+    let logo = tree.node(ids["Logo"]).unwrap();
+    assert_eq!(
+        logo.modified_client.to_rfc3339(),
+        "2021-05-28T03:06:00+00:00"
+    );
+
+    assert_eq!(
+        usb.download_zip(ids["Home"]).await?,
+        starter_zip("Home.zip")
+    );
+    assert!(usb.download_zip(Uuid::new_v4()).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn refetches_only_what_may_have_changed() -> Result<()> {
+    let (server, ids) = tablet().await;
+    let usb = UsbClient::new(&server.addr.to_string())?;
+    let temp = TempDir::new();
+    let material_path = temp.0.join("material");
+    let config = || {
+        Config::from_reader(&br#"{"site_root": "Marker", "title": "Test", "theme": "marker"}"#[..])
+    };
+
+    let downloads = |server: &StubServer| {
+        server
+            .requests()
+            .iter()
+            .filter(|path| path.starts_with("/download/"))
+            .count()
+    };
+
+    fetch(config()?, &usb, &material_path, FetchOptions::default()).await?;
+    assert_eq!(downloads(&server), 3);
+
+    let manifest = Manifest::load(&material_path)?;
+    assert_eq!(manifest.home.id, ids["Home"]);
+    let zips: Vec<PathBuf> = std::fs::read_dir(material_path.join("zip"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    assert_eq!(zips.len(), 3);
+
+    // Only the documents without a modification time are downloaded again
+    fetch(config()?, &usb, &material_path, FetchOptions::default()).await?;
+    assert_eq!(downloads(&server), 5);
+    Ok(())
+}
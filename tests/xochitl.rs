@@ -0,0 +1,75 @@
+mod common;
+
+use std::path::Path;
+
+use anyhow::Result;
+use marker_network_site_generator::{Manifest, Xochitl};
+use remarkable_cloud_api::Uuid;
+use serde_json::json;
+
+use common::TempDir;
+
+fn write_metadata(
+    dir: &Path,
+    id: Uuid,
+    name: &str,
+    doc_type: &str,
+    parent: Option<Uuid>,
+    last_modified: Option<&str>,
+) {
+    let mut metadata = json!({
+        "parent": parent.map(|p| p.to_string()).unwrap_or_default(),
+        "type": doc_type,
+        "visibleName": name,
+    });
+    if let Some(last_modified) = last_modified {
+        metadata["lastModified"] = json!(last_modified);
+    }
+    std::fs::write(
+        dir.join(format!("{}.metadata", id)),
+        serde_json::to_vec(&metadata).unwrap(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn imports_a_dump_with_missing_timestamps_and_stray_metadata() -> Result<()> {
+    let temp = TempDir::new();
+    let dump = temp.0.join("xochitl");
+    std::fs::create_dir_all(&dump)?;
+    let (marker, home, logo, posts) = (
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+    );
+    let modified = Some("1622171160000");
+    write_metadata(&dump, marker, "Marker", "CollectionType", None, modified);
+    write_metadata(&dump, home, "Home", "DocumentType", Some(marker), None);
+    write_metadata(&dump, logo, "Logo", "DocumentType", Some(marker), modified);
+    write_metadata(
+        &dump,
+        posts,
+        "Posts",
+        "CollectionType",
+        Some(marker),
+        modified,
+    );
+    // Left behind by some other tool, not a document
+    std::fs::write(dump.join("backup.metadata"), b"{}")?;
+
+    let manifest = Xochitl::load(&dump)?.import("Marker".to_string(), &temp.0.join("material"))?;
+
+    // Without a lastModified the file time stands in, not the epoch
+    let written = std::fs::metadata(dump.join(format!("{}.metadata", home)))?.modified()?;
+    assert_eq!(
+        manifest.home.modified_client.timestamp_millis(),
+        chrono::DateTime::<chrono::Utc>::from(written).timestamp_millis()
+    );
+    assert_eq!(
+        manifest.logo.modified_client.timestamp_millis(),
+        1622171160000
+    );
+    assert_eq!(Manifest::load(&temp.0.join("material"))?.home.id, home);
+    Ok(())
+}